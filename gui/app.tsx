@@ -1,4 +1,4 @@
-import { CSSProperties, useEffect, useRef } from 'react';
+import { CSSProperties, useEffect, useRef, useState } from 'react';
 import ReactDOM from 'react-dom';
 import { DbMeter } from './db-meter';
 import { LowpassCfg } from './lowpass-widget';
@@ -23,7 +23,7 @@ export function allpassMsg(a: AllpassState): WebMessage {
     gain: a.iface_allpass_gain / 100,
     naive: a.iface_allpass_naive,
   };
-  return { t: 'setControlBlock', index: DEFAULT_ALLPASS_CONTROL_BLOCK, ctl };
+  return { t: 'setControlBlock', name: DEFAULT_ALLPASS_CONTROL_BLOCK, ctl };
 }
 
 type SequencerProps = {
@@ -54,12 +54,16 @@ function Sequencer(props: SequencerProps): JSX.Element {
   return <table>{rows}</table>;
 }
 
-const DEFAULT_REASONABLE_CONTROL_BLOCK = 0
-const DEFAULT_DRUM_CONTROL_BLOCK: number = 10;
-export const DEFAULT_LOW_PASS_CONTROL_BLOCK: number = 1;
-export const DEFAULT_GAIN_CONTROL_BLOCK: number = 2;
-const DEFAULT_ALLPASS_CONTROL_BLOCK: number = 3;
-export const DEFAULT_REVERB_CONTROL_BLOCK: number = 4;
+const DEFAULT_REASONABLE_CONTROL_BLOCK = 'reasonable';
+export const DEFAULT_LOW_PASS_CONTROL_BLOCK = 'lowPass';
+export const DEFAULT_GAIN_CONTROL_BLOCK = 'gain';
+const DEFAULT_ALLPASS_CONTROL_BLOCK = 'allPass';
+export const DEFAULT_REVERB_CONTROL_BLOCK = 'reverb';
+
+// Should match drum_control_block in state.rs
+function drumControlBlock(inst: number): string {
+  return `drum${inst}`;
+}
 
 function drum_adsr(dur_scale: number): Adsr {
   return {
@@ -73,6 +77,7 @@ function drum_adsr(dur_scale: number): Adsr {
 function App(props: AppProps): JSX.Element {
   const [state, dispatch] = useEffectfulReducer<Action, State, Effect>(mkState(), reduce, doEffect);
   const wsco = useRef<WebSocketContainer | undefined>(undefined);
+  const [patchName, setPatchName] = useState('default');
 
   function doEffect(s: State, dispatch: Dispatch, e: Effect) {
     switch (e.t) {
@@ -91,6 +96,84 @@ function App(props: AppProps): JSX.Element {
     wsc.ws = ws2;
   }
 
+  // Sets up a freshly started engine the way this ui expects it
+  function initEngine() {
+    const BUS_DRY = 1;
+    const BUS_PREGAIN = 2;
+    const BUS_PRELOW = 3;
+
+    send({
+      t: 'reconfigure', specs: [
+        { t: 'midiManager', dst: BUS_DRY, ci: DEFAULT_REASONABLE_CONTROL_BLOCK },
+        { t: 'ugenGroup', dst: BUS_DRY },
+        //          { t: 'allPass', src: BUS_DRY, dst: BUS_PRELOW, ci: DEFAULT_ALLPASS_CONTROL_BLOCK },
+        { t: 'reverb', src: BUS_DRY, dst: BUS_PRELOW, ci: DEFAULT_REVERB_CONTROL_BLOCK },
+        { t: 'lowPass', src: BUS_PRELOW, dst: BUS_PREGAIN, ci: DEFAULT_LOW_PASS_CONTROL_BLOCK },
+        { t: 'gain', src: BUS_PREGAIN, dst: BUS_OUT, ci: DEFAULT_GAIN_CONTROL_BLOCK },
+
+        { t: 'meter', src: BUS_OUT },
+      ]
+    });
+    send({
+      t: 'setControlBlock', name: drumControlBlock(0), ctl: {
+        t: 'Drum', vol: 1, adsr: drum_adsr(1.0), freq_hz: 660, freq2_hz: 1,
+      }
+    });
+    send({
+      t: 'setControlBlock', name: drumControlBlock(1), ctl: {
+        t: 'Drum', vol: 1, adsr: drum_adsr(0.5), freq_hz: 1760, freq2_hz: 1000,
+      }
+    });
+    send({
+      t: 'setControlBlock', name: drumControlBlock(2), ctl: {
+        t: 'Drum', vol: 1, adsr: drum_adsr(0.1), freq_hz: 6760, freq2_hz: 5000,
+      }
+    });
+
+    send({
+      t: 'setControlBlock', name: DEFAULT_REASONABLE_CONTROL_BLOCK, ctl: {
+        t: 'Reasonable', adsr: {
+          attack_s: 0.001,
+          decay_s: 0.005,
+          sustain: 0.3,
+          release_s: 0.05,
+        },
+        wheels: { bend_range: 2, vibrato_hz: 5.5, vibrato_depth: 0.5 },
+        polyphony: { voices: 16, steal: 'oldest' },
+      }
+    });
+
+    send({
+      t: 'setControlBlock', name: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl: {
+        t: 'Low', taps: [
+          { tp: { t: 'Input' }, pos: 0, weight: 0.5 },
+          { tp: { t: 'Rec' }, pos: 1, weight: 0.5 },
+        ],
+      }
+    });
+
+    send({
+      t: 'setControlBlock', name: DEFAULT_GAIN_CONTROL_BLOCK, ctl: {
+        t: 'Gain', scale: 1.0,
+      }
+    });
+
+    send({
+      t: 'setControlBlock', name: DEFAULT_ALLPASS_CONTROL_BLOCK, ctl: {
+        t: 'All', delay: 10, gain: 0.7, naive: true,
+      }
+    });
+
+    send({
+      t: 'setControlBlock', name: DEFAULT_REVERB_CONTROL_BLOCK, ctl: {
+        t: 'Reverb',
+        roomSize: 0.5,
+        wet: 0.5,
+        width: 1.0,
+      }
+    });
+  }
+
   useEffect(() => {
     let wsc: WebSocketContainer = { ws: new WebSocket('/ws/') };
 
@@ -99,79 +182,8 @@ function App(props: AppProps): JSX.Element {
       console.log('ws opened on browser');
       wsco.current = wsc;
 
-      const BUS_DRY = 1;
-      const BUS_PREGAIN = 2;
-      const BUS_PRELOW = 3;
-
-      send({
-        t: 'reconfigure', specs: [
-          { t: 'midiManager', dst: BUS_DRY, ci: DEFAULT_REASONABLE_CONTROL_BLOCK },
-          { t: 'ugenGroup', dst: BUS_DRY },
-          //          { t: 'allPass', src: BUS_DRY, dst: BUS_PRELOW, ci: DEFAULT_ALLPASS_CONTROL_BLOCK },
-          { t: 'reverb', src: BUS_DRY, dst: BUS_PRELOW, ci: DEFAULT_REVERB_CONTROL_BLOCK },
-          { t: 'lowPass', src: BUS_PRELOW, dst: BUS_PREGAIN, ci: DEFAULT_LOW_PASS_CONTROL_BLOCK },
-          { t: 'gain', src: BUS_PREGAIN, dst: BUS_OUT, ci: DEFAULT_GAIN_CONTROL_BLOCK },
-
-          { t: 'meter', src: BUS_OUT },
-        ]
-      });
-      send({
-        t: 'setControlBlock', index: DEFAULT_DRUM_CONTROL_BLOCK, ctl: {
-          t: 'Drum', vol: 1, adsr: drum_adsr(1.0), freq_hz: 660, freq2_hz: 1,
-        }
-      });
-      send({
-        t: 'setControlBlock', index: DEFAULT_DRUM_CONTROL_BLOCK + 1, ctl: {
-          t: 'Drum', vol: 1, adsr: drum_adsr(0.5), freq_hz: 1760, freq2_hz: 1000,
-        }
-      });
-      send({
-        t: 'setControlBlock', index: DEFAULT_DRUM_CONTROL_BLOCK + 2, ctl: {
-          t: 'Drum', vol: 1, adsr: drum_adsr(0.1), freq_hz: 6760, freq2_hz: 5000,
-        }
-      });
-
-      send({
-        t: 'setControlBlock', index: DEFAULT_REASONABLE_CONTROL_BLOCK, ctl: {
-          t: 'Reasonable', adsr: {
-            attack_s: 0.001,
-            decay_s: 0.005,
-            sustain: 0.3,
-            release_s: 0.05,
-          },
-        }
-      });
-
-      send({
-        t: 'setControlBlock', index: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl: {
-          t: 'Low', taps: [
-            { tp: { t: 'Input' }, pos: 0, weight: 0.5 },
-            { tp: { t: 'Rec' }, pos: 1, weight: 0.5 },
-          ],
-        }
-      });
-
-      send({
-        t: 'setControlBlock', index: DEFAULT_GAIN_CONTROL_BLOCK, ctl: {
-          t: 'Gain', scale: 1.0,
-        }
-      });
-
-      send({
-        t: 'setControlBlock', index: DEFAULT_ALLPASS_CONTROL_BLOCK, ctl: {
-          t: 'All', delay: 10, gain: 0.7, naive: true,
-        }
-      });
-
-      send({
-        t: 'setControlBlock', index: DEFAULT_REVERB_CONTROL_BLOCK, ctl: {
-          t: 'Reverb',
-          roomSize: 0.5,
-          wet: 0.5,
-        }
-      });
-
-
+      // Find out what the engine is already doing before we touch it
+      send({ t: 'getState' });
     }
 
     wsc.ws.onclose = () => {
@@ -186,6 +198,20 @@ function App(props: AppProps): JSX.Element {
         if (msg.t == 'meter') {
           dispatch({ t: 'setMeterValues', msg });
         }
+        else if (msg.t == 'engineStats') {
+          dispatch({ t: 'setEngineStats', msg });
+        }
+        else if (msg.t == 'state') {
+          if (Object.keys(msg.control_blocks).length == 0) {
+            initEngine();
+          }
+          else {
+            dispatch({ t: 'setEngineState', msg });
+          }
+        }
+        else if (msg.t == 'error') {
+          console.log(`engine refused a message: ${msg.description}`);
+        }
       } catch (e) {
         console.log(`couldn't parse ${message.data}`);
       }
@@ -222,13 +248,16 @@ function App(props: AppProps): JSX.Element {
 
   //  <Chart lowp_param={0.50} />
 
-  const { connected, iface_gain, iface_highpass, allpass, meterData, text } = state;
+  const { connected, iface_gain, iface_highpass, allpass, meterData, engineStats, text } = state;
 
   const rollEditorProps = state.rollEditorState;
 
   return <div>
     <button disabled={!connected} onMouseDown={() => { send({ t: 'drum' }) }}>Action</button><br />
     <button disabled={!connected} onMouseDown={() => { send({ t: 'quit' }) }}>Quit</button><br />
+    patch: <input value={patchName} onInput={(e) => setPatchName((e.target as HTMLInputElement).value)} />
+    <button disabled={!connected} onMouseDown={() => { send({ t: 'savePatch', name: patchName }) }}>Save</button>
+    <button disabled={!connected} onMouseDown={() => { send({ t: 'loadPatch', name: patchName }) }}>Load</button><br />
     <input disabled={!connected} type="range" min="1" max="99" value={iface_gain} onInput={gainOnInput} />
     <LowpassCfg
       cfg={state.lowpassState}
@@ -258,6 +287,8 @@ function App(props: AppProps): JSX.Element {
     <br />
     <DbMeter label="RMS" value={meterData.level} /><br />
     <DbMeter label="Peak" value={meterData.peak} /><br />
+    load: {Math.round(engineStats.load * 100)}% (peak {Math.round(engineStats.peak_load * 100)}%),
+    xruns: {engineStats.xruns}<br />
     <RollEditor {...rollEditorProps} dispatch={dispatch} />
   </div>;
 }
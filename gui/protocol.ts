@@ -10,3 +10,5 @@ export { SynthMessage } from '../bindings/SynthMessage';
 import { SynthMessage } from '../bindings/SynthMessage';
 
 export type MeterData = Omit<SynthMessage & { t: 'meter' }, 't'>;
+export type EngineState = Omit<SynthMessage & { t: 'state' }, 't'>;
+export type EngineStats = Omit<SynthMessage & { t: 'engineStats' }, 't'>;
@@ -27,7 +27,7 @@ function reduce_inner(state: State, action: Action): State {
     case 'setGain': {
       const gain = MAX_GAIN * action.iface_gain / 100;
       const ctl: ControlBlock = { t: 'Gain', scale: gain };
-      const msg: WebMessage = { t: 'setControlBlock', index: DEFAULT_GAIN_CONTROL_BLOCK, ctl };
+      const msg: WebMessage = { t: 'setControlBlock', name: DEFAULT_GAIN_CONTROL_BLOCK, ctl };
       return produce(state, s => {
         s.iface_gain = action.iface_gain;
         s.outbox.push(msg);
@@ -42,7 +42,7 @@ function reduce_inner(state: State, action: Action): State {
           { tp: { t: 'Input' }, pos: 1, weight: -alpha },
         ]
       };
-      const msg: WebMessage = { t: 'setControlBlock', index: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl };
+      const msg: WebMessage = { t: 'setControlBlock', name: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl };
       return produce(state, s => {
         s.iface_highpass = action.iface_highpass;
         s.outbox.push(msg);
@@ -86,6 +86,39 @@ function reduce_inner(state: State, action: Action): State {
         s.meterData = action.msg;
       });
     }
+    case 'setEngineStats': {
+      return produce(state, s => {
+        s.engineStats = action.msg;
+      });
+    }
+    case 'setEngineState': {
+      // Make the controls reflect whatever the engine is already doing
+      const { control_blocks, sequencer } = action.msg;
+      return produce(state, s => {
+        s.table = sequencer;
+        for (const ctl of Object.values(control_blocks)) {
+          switch (ctl.t) {
+            case 'Gain': s.iface_gain = ctl.scale * 100 / MAX_GAIN; break;
+            case 'Reverb':
+              s.iface_roomsize = ctl.roomSize * 100;
+              s.iface_wet = ctl.wet * 100;
+              break;
+            case 'All':
+              s.allpass = {
+                iface_allpass_delay: ctl.delay,
+                iface_allpass_gain: ctl.gain * 100,
+                iface_allpass_naive: ctl.naive,
+              };
+              break;
+            case 'Low':
+              s.lowpassState = ctl.taps
+                .filter(tap => tap.tp.t == 'Rec')
+                .map(({ pos, weight }) => ({ pos, weight: weight * 100 }));
+              break;
+          }
+        }
+      });
+    }
     case 'setLowpassState': {
       const { lowpassState } = action;
 
@@ -104,7 +137,7 @@ function reduce_inner(state: State, action: Action): State {
         taps: [...taps, { pos: 0, weight: selfWeight, tp: { t: 'Input' } }],
       };
 
-      const msg: WebMessage = { t: 'setControlBlock', index: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl };
+      const msg: WebMessage = { t: 'setControlBlock', name: DEFAULT_LOW_PASS_CONTROL_BLOCK, ctl };
 
       return produce(state, s => {
         s.lowpassState = lowpassState;
@@ -119,8 +152,9 @@ function reduce_inner(state: State, action: Action): State {
         t: 'Reverb',
         roomSize: newState.iface_roomsize / 100,
         wet: newState.iface_wet / 100,
+        width: 1.0,
       };
-      const msg: WebMessage = { t: 'setControlBlock', index: DEFAULT_REVERB_CONTROL_BLOCK, ctl };
+      const msg: WebMessage = { t: 'setControlBlock', name: DEFAULT_REVERB_CONTROL_BLOCK, ctl };
       return produce(newState, s => {
         s.outbox.push(msg);
       });
@@ -133,8 +167,9 @@ function reduce_inner(state: State, action: Action): State {
         t: 'Reverb',
         roomSize: newState.iface_roomsize / 100,
         wet: newState.iface_wet / 100,
+        width: 1.0,
       };
-      const msg: WebMessage = { t: 'setControlBlock', index: DEFAULT_REVERB_CONTROL_BLOCK, ctl };
+      const msg: WebMessage = { t: 'setControlBlock', name: DEFAULT_REVERB_CONTROL_BLOCK, ctl };
       return produce(newState, s => {
         s.outbox.push(msg);
       });
@@ -1,5 +1,5 @@
 import { LowpassWidgetState } from './lowpass-widget';
-import { MeterData, WebMessage } from './protocol';
+import { EngineState, EngineStats, MeterData, WebMessage } from './protocol';
 import { RollEditorState } from './roll';
 import { RollAction, rollDims } from './roll-util';
 import { score } from './score';
@@ -20,6 +20,8 @@ export type Action =
   | { t: 'setAllpassGain', iface_allpass_gain: number }
   | { t: 'setAllpassNaive', iface_allpass_naive: boolean }
   | { t: 'setMeterValues', msg: MeterData }
+  | { t: 'setEngineStats', msg: EngineStats }
+  | { t: 'setEngineState', msg: EngineState }
   | { t: 'setLowpassState', lowpassState: LowpassWidgetState }
   | { t: 'setRoomSize', iface_roomsize: number }
   | { t: 'setWet', iface_wet: number }
@@ -49,6 +51,7 @@ export type State = {
   allpass: AllpassState;
   outbox: WebMessage[],
   meterData: MeterData,
+  engineStats: EngineStats,
   lowpassState: LowpassWidgetState,
   text: string,
   rollEditorState: RollEditorState,
@@ -73,6 +76,7 @@ export function mkState(): State {
       iface_allpass_naive: true,
     },
     meterData: { level: 0, peak: 0 },
+    engineStats: { xruns: 0, load: 0, peak_load: 0 },
     lowpassState: [{ pos: 1, weight: 90 }, { pos: 2620, weight: 10 }],
     text: '',
     rollEditorState: {
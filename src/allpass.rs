@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::state::{ControlBlock, ControlBlocks, GenState};
+use crate::param::{self, Key, Params};
+use crate::state::{
+  read_channel, ugen_channels, write_channel, ControlBlock, ControlBlocks, GenState,
+};
 use crate::ugen::Ugen;
 
 const HISTORY_SIZE: usize = 35000;
@@ -11,11 +14,13 @@ pub struct AllpassState {
   src: usize,
   dst: usize,
   ci: usize,
+  channels: usize,
   ix: usize,
-  memory_rec: Vec<f32>,
+  // One ring buffer per channel
+  memory_rec: Vec<Vec<f32>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
 #[ts(export)]
@@ -25,23 +30,34 @@ pub struct AllpassControlBlock {
   pub naive: bool,
 }
 
+impl Params for AllpassControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("gain", []) => Some(&mut self.gain),
+      _ => None,
+    }
+  }
+}
+
 impl AllpassState {
-  pub fn new(src: usize, dst: usize, ci: usize) -> Self {
+  pub fn new(src: usize, dst: usize, ci: usize, channels: Option<usize>) -> Self {
+    let channels = ugen_channels(channels);
     AllpassState {
       src,
       dst,
       ci,
+      channels,
       ix: 0,
-      memory_rec: vec![0.; HISTORY_SIZE],
+      memory_rec: vec![vec![0.; HISTORY_SIZE]; channels],
     }
   }
 
-  fn do_tap(&self, delay: i32) -> f32 {
-    self.memory_rec[((self.ix as i32) - delay).rem_euclid(HISTORY_SIZE as i32) as usize]
+  fn do_tap(&self, ch: usize, delay: i32) -> f32 {
+    self.memory_rec[ch][((self.ix as i32) - delay).rem_euclid(HISTORY_SIZE as i32) as usize]
   }
 
   fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &AllpassControlBlock) -> bool {
-    for bus_ix in 0..gen.audio_bus[0].len() {
+    for bus_ix in 0..gen.audio_bus[0][0].len() {
       // bus_ix is the index into the past output (memory_rec) of this
       // ugen.
 
@@ -50,23 +66,25 @@ impl AllpassState {
         delay,
         naive,
       } = ctl;
-      // Make sure the current input value is in memory_input at the
-      // current time (because do_tap might need to read it)
-      let dry = gen.audio_bus[self.src][bus_ix];
-      // tapv = (Δ + gΔ² + g³Δ² + ⋯)dry
-      let tapv = self.do_tap(*delay as i32);
-      // wet = dry/(1-gΔ) = (1 + gΔ + (gΔ)² + (gΔ)³ + ⋯)dry
-      let wet = dry + g * tapv;
-      self.memory_rec[self.ix] = wet;
+      for ch in 0..self.channels {
+        // Make sure the current input value is in memory_input at the
+        // current time (because do_tap might need to read it)
+        let dry = read_channel(&gen.audio_bus[self.src], self.channels, ch, bus_ix);
+        // tapv = (Δ + gΔ² + g³Δ² + ⋯)dry
+        let tapv = self.do_tap(ch, *delay as i32);
+        // wet = dry/(1-gΔ) = (1 + gΔ + (gΔ)² + (gΔ)³ + ⋯)dry
+        let wet = dry + g * tapv;
+        self.memory_rec[ch][self.ix] = wet;
 
-      // Schroeder & Logan 1960 "'Colorless' Artificial Reverberation"
-      let out = if *naive {
-        dry + g * tapv
-      } else {
-        -g * dry + (1.0 - g * g) * tapv
-      };
+        // Schroeder & Logan 1960 "'Colorless' Artificial Reverberation"
+        let out = if *naive {
+          dry + g * tapv
+        } else {
+          -g * dry + (1.0 - g * g) * tapv
+        };
 
-      gen.audio_bus[self.dst][bus_ix] = out;
+        write_channel(&mut gen.audio_bus[self.dst], self.channels, ch, bus_ix, out);
+      }
       self.ix = (self.ix + 1) % HISTORY_SIZE;
     }
     true
@@ -1,24 +1,89 @@
-use crate::consts::BUS_OUT;
-use crate::synth::Synth;
-use crate::util::{depoison, JoinHandle};
-use crate::{Args, State, StateGuard};
-use alsa::pcm::{Access, Format, HwParams, PCM};
-use alsa::{Direction, ValueOr};
+use crate::backend::{self, AudioBackend, BackendKind};
+use crate::consts::BUS_CHANNELS;
+use crate::engine::{Engine, Telemetry};
+use crate::synth::interleave_out;
+use crate::util::JoinHandle;
+use crate::webserver::SynthMessage;
+use crate::Args;
 use dbus::blocking as dbus;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
-use std::sync::mpsc::channel;
-use std::sync::MutexGuard;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct AudioService {
-  pub render_thread: JoinHandle,
+  pub config: AudioConfig,
+  // Moved into the render thread by start()
+  backend: Option<Box<dyn AudioBackend>>,
+  // Held only so that the sound card stays reserved for as long as
+  // we're running.
+  reservation: Option<Reservation>,
 }
 
-pub const CHANNELS: u32 = 2;
-pub const BUF_SIZE: usize = 128;
-pub const HALF_BUF_SIZE: usize = BUF_SIZE / 2;
+pub const CHANNELS: u32 = BUS_CHANNELS as u32;
+
+// How fast, and in what size chunks, we generate audio. These start
+// out as whatever was asked for on the command line, but the backend
+// gets the final say.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioConfig {
+  pub sample_rate_hz: u32,
+  // Frames generated per iteration of the render loop
+  pub period_frames: usize,
+}
+
+impl AudioConfig {
+  pub fn tick_s(&self) -> f32 {
+    1.0 / (self.sample_rate_hz as f32)
+  }
+
+  pub fn period_s(&self) -> f32 {
+    (self.period_frames as f32) * self.tick_s()
+  }
+}
+
+// How often the render loop reports EngineStats
+const STATS_INTERVAL_s: f32 = 1.0;
+
+// Keeps track of how much of each period's time budget we spend
+// rendering, as opposed to waiting for the backend.
+struct RenderStats {
+  budget: Duration,
+  periods_per_report: usize,
+  periods: usize,
+  total: Duration,
+  max: Duration,
+}
+
+impl RenderStats {
+  fn new(config: AudioConfig) -> RenderStats {
+    RenderStats {
+      budget: Duration::from_secs_f32(config.period_s()),
+      periods_per_report: ((STATS_INTERVAL_s / config.period_s()) as usize).max(1),
+      periods: 0,
+      total: Duration::ZERO,
+      max: Duration::ZERO,
+    }
+  }
+
+  // Returns a report once every STATS_INTERVAL_s
+  fn record(&mut self, elapsed: Duration, xruns: usize) -> Option<SynthMessage> {
+    self.periods += 1;
+    self.total += elapsed;
+    self.max = self.max.max(elapsed);
+    if self.periods < self.periods_per_report {
+      return None;
+    }
+    let budget_s = self.budget.as_secs_f32();
+    let msg = SynthMessage::EngineStats {
+      xruns,
+      load: self.total.as_secs_f32() / (self.periods as f32 * budget_s),
+      peak_load: self.max.as_secs_f32() / budget_s,
+    };
+    self.periods = 0;
+    self.total = Duration::ZERO;
+    self.max = Duration::ZERO;
+    Some(msg)
+  }
+}
 
 struct Reservation {
   conn: dbus::Connection,
@@ -57,98 +122,67 @@ fn dbus_reserve(card: u8) -> Result<Reservation, Box<dyn Error>> {
   Ok(Reservation { conn })
 }
 
-fn vi_to_u8(v: &[i16]) -> &[u8] {
-  unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, v.len() * 2) }
-}
-
 impl AudioService {
-  pub fn new(args: &Args, state: &StateGuard, mut synth: Synth) -> anyhow::Result<AudioService> {
-    let args = args.clone();
-    let card = args.sound_card;
-    let reservation = dbus_reserve(card);
-    if let Err(e) = reservation {
-      println!("Warning: {:?}", e);
+  // Reserves and opens the audio device, but doesn't start rendering
+  // yet, so that the caller can find out the negotiated config first.
+  pub fn new(args: &Args) -> anyhow::Result<AudioService> {
+    let reservation = match (args.backend, args.sound_card) {
+      (BackendKind::Alsa, Some(card)) => match dbus_reserve(card) {
+        Ok(r) => Some(r),
+        Err(e) => {
+          println!("Warning: {:?}", e);
+          None
+        },
+      },
+      _ => None,
     };
 
+    let backend = backend::open(args, args.audio_config())?;
+    Ok(AudioService {
+      config: backend.config(),
+      backend: Some(backend),
+      reservation,
+    })
+  }
+
+  // Hands the engine over to a new render thread, which owns it from
+  // then on.
+  pub fn start(&mut self, args: &Args, mut engine: Engine) -> anyhow::Result<JoinHandle> {
+    let args = args.clone();
+    let config = self.config;
+    let mut backend = self
+      .backend
+      .take()
+      .ok_or_else(|| anyhow::anyhow!("audio service already started"))?;
+
     fn do_profile(args: &Args, iters: usize) -> bool {
       match args.profile_interval {
         None => false,
-        Some(interval) => iters % interval == 0,
+        Some(interval) => iters.is_multiple_of(interval.get()),
       }
     }
 
-    let sg = state.clone();
-
-    let mut file = File::create("/tmp/a.sw")?;
-    let (send, recv) = channel::<Vec<i16>>();
-    let file_writing_thread = std::thread::spawn(move || -> anyhow::Result<()> {
-      let mut n = 0;
-      for ref x in recv.iter() {
-        n += 1;
-        if n % 2000 == 0 {
-          n = 0;
-          println!("on channel recv'ed {}", x[0]);
-        }
-        let bytes = vi_to_u8(x);
-        file.write_all(bytes)?;
-      }
-      Ok(())
-    });
-
     let render_thread = std::thread::spawn(move || -> anyhow::Result<()> {
-      // Initialize alsa
-      let device_name = format!("hw:{card}");
-      let pcm = PCM::new(&device_name, Direction::Playback, false)?;
-
-      let hwp = HwParams::any(&pcm)?;
-      hwp.set_channels(CHANNELS)?;
-      hwp.set_rate(44100, ValueOr::Nearest)?;
-      hwp.set_format(Format::s16())?;
-      hwp.set_access(Access::RWInterleaved)?;
-      hwp.set_buffer_size(BUF_SIZE as i64)?;
-      pcm.hw_params(&hwp)?;
-      let io = pcm.io_i16()?;
-
-      let hwp = pcm.hw_params_current()?;
-      let buffer_size = hwp.get_buffer_size();
-      match buffer_size {
-        Ok(s) => println!("buffer size is {s}"),
-        Err(_) => {},
-      }
-
-      let swp = pcm.sw_params_current()?;
-      swp.set_start_threshold(hwp.get_buffer_size()?)?;
-      pcm.sw_params(&swp)?;
-
       let mut iters: usize = 0;
-      let mut buf = [0i16; BUF_SIZE];
+      let mut buf = vec![0f32; config.period_frames * (CHANNELS as usize)];
       let mut now: Instant = Instant::now();
+      let mut stats = RenderStats::new(config);
 
       loop {
+        let render_start = Instant::now();
         {
           if do_profile(&args, iters) {
             now = Instant::now();
           }
-          let mut s: MutexGuard<State> = depoison(sg.lock())?;
-          if !s.going {
+          engine.process_commands();
+          if !engine.going() {
             break;
           }
 
-          fn convert_sample(samp_f32: f32) -> i16 {
-            (samp_f32 * 32767.0) as i16
-          }
-
-          synth.synth_buf(&mut s);
-          for (ix, ch) in buf.chunks_mut(CHANNELS as usize).enumerate() {
-            let samp = convert_sample(s.audio_bus[BUS_OUT][ix]);
-            ch[0] = samp;
-            ch[1] = samp;
-          }
-
-          if s.write_to_file {
-            send.send(buf.to_vec())?;
-          }
+          engine.render();
+          interleave_out(&engine.state, &mut buf);
         }
+        let render_time = render_start.elapsed();
         if do_profile(&args, iters) {
           println!("Elapsed: {:.2?}", now.elapsed());
           println!("Time: {:.2?}", now);
@@ -157,13 +191,16 @@ impl AudioService {
 
         iters += 1;
 
-        let _written = io.writei(&buf[..]);
+        backend.write(&buf[..])?;
+
+        if let Some(msg) = stats.record(render_time, backend.xruns()) {
+          engine.state.send_telemetry(Telemetry::Synth(msg));
+        }
       }
 
-      // Wait for the stream to finish playback.
-      pcm.drain()?;
+      backend.drain()?;
       Ok(())
     });
-    Ok(AudioService { render_thread })
+    Ok(render_thread)
   }
 }
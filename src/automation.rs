@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::audio::AudioConfig;
+use crate::param::{Key, Params};
+use crate::state::State;
+
+// How a lane gets from one breakpoint to the next
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum Curve {
+  #[default]
+  Linear,
+  // Equal ratios in equal times, which is what pitches and gains want.
+  // Both ends have to be nonzero and of the same sign.
+  Exp,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Breakpoint {
+  // From the start of the lane
+  pub time_s: f32,
+  pub value: f32,
+  // Of the segment that ends here
+  #[serde(default)]
+  pub curve: Curve,
+}
+
+// Moves one number in a control block along a breakpoint envelope.
+// Before the first breakpoint and after the last, the number stays
+// where they are. A looped lane starts again whenever the sequencer
+// starts its pattern again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Lane {
+  pub block: String,
+  // As for SetParam; see param.rs
+  pub path: String,
+  pub points: Vec<Breakpoint>,
+  #[serde(default)]
+  pub looped: bool,
+}
+
+pub type NamedLanes = BTreeMap<String, Lane>;
+
+pub type LaneId = usize;
+
+#[derive(Clone, Copy, Debug)]
+struct Point {
+  frame: u64,
+  value: f32,
+  curve: Curve,
+}
+
+// A Lane as the audio thread runs it, with the control block as a
+// slot, the path parsed, and times in frames.
+#[derive(Debug)]
+pub struct LaneState {
+  // A lane that's sent again with the same id carries on from where it
+  // was, rather than starting over
+  pub id: LaneId,
+  index: usize,
+  keys: Vec<Key>,
+  points: Vec<Point>,
+  looped: bool,
+  // The frame it started (or last looped) at
+  start: u64,
+  // Its value at every frame of the current period
+  values: Vec<f32>,
+}
+
+pub type Automation = Vec<LaneState>;
+
+impl LaneState {
+  // `lane` should already have been validated
+  pub fn new(id: LaneId, lane: &Lane, index: usize, config: AudioConfig) -> LaneState {
+    let points = lane
+      .points
+      .iter()
+      .map(|p| Point {
+        frame: (p.time_s * config.sample_rate_hz as f32).round() as u64,
+        value: p.value,
+        curve: p.curve,
+      })
+      .collect();
+    LaneState {
+      id,
+      index,
+      keys: crate::param::parse_path(&lane.path),
+      points,
+      looped: lane.looped,
+      start: 0,
+      values: vec![0.0; config.period_frames],
+    }
+  }
+
+  // Whether it moves the number at `path`, given as field names
+  fn moves(&self, index: usize, path: &[&str]) -> bool {
+    self.index == index
+      && self.keys.len() == path.len()
+      && self
+        .keys
+        .iter()
+        .zip(path)
+        .all(|(key, name)| matches!(key, Key::Field(field) if field == name))
+  }
+
+  // The value `t` frames after the lane started
+  fn value_at(&self, t: u64) -> f32 {
+    let next = self.points.partition_point(|p| p.frame <= t);
+    if next == 0 {
+      return self.points[0].value;
+    }
+    let a = &self.points[next - 1];
+    let Some(b) = self.points.get(next) else {
+      return a.value;
+    };
+    let x = (t - a.frame) as f32 / (b.frame - a.frame) as f32;
+    match b.curve {
+      Curve::Linear => a.value + (b.value - a.value) * x,
+      Curve::Exp => a.value * (b.value / a.value).powf(x),
+    }
+  }
+}
+
+// Lanes in `new` that were already running keep their place; the
+// rest start now.
+pub fn carry_over(new: &mut Automation, old: &Automation, frame: u64) {
+  for lane in new.iter_mut() {
+    lane.start = match old.iter().find(|o| o.id == lane.id) {
+      Some(o) => o.start,
+      None => frame,
+    };
+  }
+}
+
+// Called when the sequencer starts its pattern
+pub fn restart_loops(s: &mut State) {
+  for lane in s.automation.iter_mut().filter(|lane| lane.looped) {
+    lane.start = s.frame;
+  }
+}
+
+// Works out every lane's value for each frame of the period starting
+// at s.frame, and writes the first into its control block. Ugens that
+// smooth a number follow its lane frame by frame instead; see Target.
+pub fn run(s: &mut State) {
+  for lane in s.automation.iter_mut() {
+    let t = s.frame.saturating_sub(lane.start);
+    for ix in 0..lane.values.len() {
+      lane.values[ix] = lane.value_at(t + ix as u64);
+    }
+    let param = s
+      .control_blocks
+      .get_mut(lane.index)
+      .and_then(|ctl| ctl.as_mut())
+      .and_then(|ctl| ctl.param_mut(&lane.keys));
+    // The block may have been replaced by one of a different kind
+    if let Some(param) = param {
+      *param = lane.values[0];
+    }
+  }
+}
+
+// A number in a control block, as a ugen sees it through a period:
+// where the block has it, and, if a lane moves it, where the lane has
+// it at every frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Target<'a> {
+  value: f32,
+  lane: Option<&'a [f32]>,
+}
+
+impl Target<'_> {
+  // Where it should be `ix` frames into the period. Anything else
+  // moving it too, like modulation, keeps its share.
+  pub fn at(&self, ix: usize) -> f32 {
+    match self.lane {
+      Some(values) => self.value + values[ix] - values[0],
+      None => self.value,
+    }
+  }
+
+  pub fn automated(&self) -> bool {
+    self.lane.is_some()
+  }
+}
+
+// `value`, found at `path` in control block `index`, as a Target. If
+// more than one lane moves it, the last one wins, as it does in run.
+pub fn target<'a>(
+  automation: &'a Automation,
+  index: usize,
+  path: &[&str],
+  value: f32,
+) -> Target<'a> {
+  let lane = automation
+    .iter()
+    .rev()
+    .find(|lane| lane.moves(index, path))
+    .map(|lane| &lane.values[..]);
+  Target { value, lane }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{target, Breakpoint, Curve, Lane, LaneState};
+  use crate::audio::AudioConfig;
+
+  fn lane(curve: Curve) -> LaneState {
+    let points = vec![
+      Breakpoint {
+        time_s: 1.0,
+        value: 1.0,
+        curve: Curve::Linear,
+      },
+      Breakpoint {
+        time_s: 3.0,
+        value: 4.0,
+        curve,
+      },
+    ];
+    let lane = Lane {
+      block: "gain".to_string(),
+      path: "scale".to_string(),
+      points,
+      looped: false,
+    };
+    let config = AudioConfig {
+      sample_rate_hz: 10,
+      period_frames: 1,
+    };
+    LaneState::new(0, &lane, 0, config)
+  }
+
+  #[test]
+  fn follows_breakpoints() {
+    let linear = lane(Curve::Linear);
+    assert_eq!(linear.value_at(0), 1.0);
+    assert_eq!(linear.value_at(10), 1.0);
+    assert_eq!(linear.value_at(20), 2.5);
+    assert_eq!(linear.value_at(30), 4.0);
+    assert_eq!(linear.value_at(1000), 4.0);
+
+    let exp = lane(Curve::Exp);
+    assert!((exp.value_at(20) - 2.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn targets_follow_lanes_through_the_period() {
+    let mut lane = lane(Curve::Linear);
+    lane.values = vec![1.0, 2.0, 3.0];
+    let automation = vec![lane];
+    // e.g. modulation has moved it up from where the lane has it
+    let scale = target(&automation, 0, &["scale"], 1.5);
+    assert_eq!(scale.at(0), 1.5);
+    assert_eq!(scale.at(2), 3.5);
+    let other = target(&automation, 0, &["wet"], 0.5);
+    assert!(!other.automated());
+    assert_eq!(other.at(2), 0.5);
+  }
+}
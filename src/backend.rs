@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
+
+use alsa::pcm::{Access, Format, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+
+use crate::audio::{AudioConfig, CHANNELS};
+use crate::wav::{convert_sample, WavWriter};
+use crate::Args;
+
+// Where the render loop sends its audio.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+  // A real sound card
+  Alsa,
+  // Discard audio, but keep time as if a sound card were consuming it
+  Null,
+  // Write a WAV file, paced like the null backend
+  File,
+}
+
+pub trait AudioBackend: Send {
+  // Consume one buffer of interleaved samples, blocking until the
+  // backend is ready for more.
+  fn write(&mut self, buf: &[f32]) -> anyhow::Result<()>;
+
+  // Called once, after the last write.
+  fn drain(&mut self) -> anyhow::Result<()>;
+
+  // The sample rate and period size we actually got, which may
+  // differ from what we asked for.
+  fn config(&self) -> AudioConfig;
+
+  // How many times so far the device has run out of audio to play
+  fn xruns(&self) -> usize {
+    0
+  }
+}
+
+pub fn open(args: &Args, requested: AudioConfig) -> anyhow::Result<Box<dyn AudioBackend>> {
+  match args.backend {
+    BackendKind::Alsa => {
+      let card = args
+        .sound_card
+        .ok_or_else(|| anyhow!("--sound-card is required for the alsa backend"))?;
+      Ok(Box::new(AlsaBackend::new(card, requested)?))
+    },
+    BackendKind::Null => Ok(Box::new(NullBackend::new(requested))),
+    BackendKind::File => Ok(Box::new(FileBackend::new(args, requested)?)),
+  }
+}
+
+pub struct AlsaBackend {
+  pcm: PCM,
+  config: AudioConfig,
+  buf: Vec<i16>,
+  xruns: usize,
+}
+
+impl AlsaBackend {
+  pub fn new(card: u8, requested: AudioConfig) -> anyhow::Result<AlsaBackend> {
+    let device_name = format!("hw:{card}");
+    let pcm = PCM::new(&device_name, Direction::Playback, false)?;
+
+    let config = {
+      // We ask for a device buffer two periods long, so that the card
+      // can play one period while we render the next.
+      let hwp = HwParams::any(&pcm)?;
+      hwp.set_channels(CHANNELS)?;
+      hwp.set_rate(requested.sample_rate_hz, ValueOr::Nearest)?;
+      hwp.set_format(Format::s16())?;
+      hwp.set_access(Access::RWInterleaved)?;
+      hwp.set_buffer_size_near(2 * requested.period_frames as i64)?;
+      pcm.hw_params(&hwp)?;
+
+      let hwp = pcm.hw_params_current()?;
+      let buffer_size = hwp.get_buffer_size()?;
+      println!("buffer size is {buffer_size}");
+
+      let swp = pcm.sw_params_current()?;
+      swp.set_start_threshold(buffer_size)?;
+      pcm.sw_params(&swp)?;
+
+      AudioConfig {
+        sample_rate_hz: hwp.get_rate()?,
+        period_frames: (buffer_size as usize / 2).max(1),
+      }
+    };
+    if config != requested {
+      println!("Warning: asked alsa for {:?}, got {:?}", requested, config);
+    }
+
+    Ok(AlsaBackend {
+      pcm,
+      config,
+      buf: vec![0; config.period_frames * (CHANNELS as usize)],
+      xruns: 0,
+    })
+  }
+}
+
+impl AudioBackend for AlsaBackend {
+  fn write(&mut self, buf: &[f32]) -> anyhow::Result<()> {
+    for (out, samp) in self.buf.iter_mut().zip(buf.iter()) {
+      *out = convert_sample(*samp);
+    }
+    let io = self.pcm.io_i16()?;
+    let mut remaining = &self.buf[..buf.len()];
+    while !remaining.is_empty() {
+      match io.writei(remaining) {
+        Ok(frames) => remaining = &remaining[frames * (CHANNELS as usize)..],
+        Err(e) => {
+          if is_xrun(&e) {
+            self.xruns += 1;
+          }
+          // This re-prepares the device after an underrun or a
+          // suspend, and hands back any other error untouched. Once
+          // prepared, the next writei restarts playback.
+          self.pcm.try_recover(e, true)?;
+        },
+      }
+    }
+    Ok(())
+  }
+
+  fn drain(&mut self) -> anyhow::Result<()> {
+    // Wait for the stream to finish playback.
+    self.pcm.drain()?;
+    Ok(())
+  }
+
+  fn config(&self) -> AudioConfig {
+    self.config
+  }
+
+  fn xruns(&self) -> usize {
+    self.xruns
+  }
+}
+
+// Alsa reports an underrun as EPIPE
+fn is_xrun(e: &alsa::Error) -> bool {
+  std::io::Error::from_raw_os_error(e.errno()).kind() == std::io::ErrorKind::BrokenPipe
+}
+
+// Stands in for the sound card's clock: wait() blocks until the time
+// at which a device would have finished playing the previous buffer.
+struct BufferClock {
+  config: AudioConfig,
+  period: Duration,
+  deadline: Option<Instant>,
+}
+
+impl BufferClock {
+  fn new(config: AudioConfig) -> BufferClock {
+    BufferClock {
+      config,
+      period: Duration::from_secs_f32(config.period_s()),
+      deadline: None,
+    }
+  }
+
+  fn wait(&mut self) {
+    let now = Instant::now();
+    let deadline = self.deadline.unwrap_or(now);
+    if deadline > now {
+      std::thread::sleep(deadline - now);
+    }
+    // If we've fallen behind, don't try to catch up by racing.
+    self.deadline = Some(deadline.max(now) + self.period);
+  }
+}
+
+pub struct NullBackend {
+  clock: BufferClock,
+}
+
+impl NullBackend {
+  pub fn new(config: AudioConfig) -> NullBackend {
+    NullBackend {
+      clock: BufferClock::new(config),
+    }
+  }
+}
+
+impl AudioBackend for NullBackend {
+  fn write(&mut self, buf: &[f32]) -> anyhow::Result<()> {
+    self.clock.wait();
+    Ok(())
+  }
+
+  fn drain(&mut self) -> anyhow::Result<()> {
+    Ok(())
+  }
+
+  fn config(&self) -> AudioConfig {
+    self.clock.config
+  }
+}
+
+pub struct FileBackend {
+  clock: BufferClock,
+  wav: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl FileBackend {
+  pub fn new(args: &Args, config: AudioConfig) -> anyhow::Result<FileBackend> {
+    let path = args
+      .output
+      .as_ref()
+      .ok_or_else(|| anyhow!("--output is required for the file backend"))?;
+    let file = File::create(path).with_context(|| format!("creating output {}", path.display()))?;
+    let wav = WavWriter::new(
+      BufWriter::new(file),
+      CHANNELS as u16,
+      config.sample_rate_hz,
+      args.format,
+    )?;
+    Ok(FileBackend {
+      clock: BufferClock::new(config),
+      wav: Some(wav),
+    })
+  }
+}
+
+impl AudioBackend for FileBackend {
+  fn write(&mut self, buf: &[f32]) -> anyhow::Result<()> {
+    if let Some(wav) = &mut self.wav {
+      wav.write_samples(buf)?;
+    }
+    self.clock.wait();
+    Ok(())
+  }
+
+  fn drain(&mut self) -> anyhow::Result<()> {
+    if let Some(wav) = self.wav.take() {
+      wav.finish()?;
+    }
+    Ok(())
+  }
+
+  fn config(&self) -> AudioConfig {
+    self.clock.config
+  }
+}
@@ -1,8 +1,10 @@
-pub const BOTTOM_NOTE: u8 = 21;
-pub const NUM_KEYS: usize = 88;
+pub const NUM_KEYS: usize = 128; // every midi note
 
-pub const SAMPLE_RATE_hz: f32 = 44_100.0;
+pub const DEFAULT_SAMPLE_RATE_hz: u32 = 44_100;
+pub const DEFAULT_PERIOD_FRAMES: usize = 64;
+pub const DEFAULT_PARAM_RAMP_s: f32 = 0.02; // see smooth.rs
 pub const AUDIO_BUS_LENGTH: usize = 16;
+pub const BUS_CHANNELS: usize = 2; // every bus is stereo
 
 pub const BUS_OUT: usize = 0; // this is genuinely special, because this is what we connect to output
 pub const BUS_DRY: usize = 1; // XXX this should be merely conventional and should eventually be deleted
@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use rtrb::PushError;
+
+use crate::audio::AudioConfig;
+use crate::automation::{Lane, LaneId, LaneState, NamedLanes};
+use crate::engine::{CommandProducer, EngineCommand, Slot};
+use crate::fade::Fade;
+use crate::graph;
+use crate::midi::Message;
+use crate::midi_learn::{MidiBinding, MidiTarget};
+use crate::modulation::{ModRoute, Modulation};
+use crate::param;
+use crate::patch::Patch;
+use crate::sequencer::Sequencer;
+use crate::state::{
+  new_control_blocks, ControlBlock, ControlSlots, NamedControlBlocks, INITIAL_CONTROL_BLOCKS,
+};
+use crate::ugen::{Node, NodeId, UgenSpec, UgenState};
+use crate::validate::{self, ValidationError};
+use crate::wavetables::Wavetables;
+use crate::webserver::{ClientId, Clients, SynthMessage};
+
+// State shared between the midi, sequencer, web and stdin threads.
+// The audio thread never takes this lock; everything it needs to know
+// gets sent to it as an EngineCommand.
+#[derive(Debug)]
+pub struct ControlState {
+  pub going: bool,
+  pub audio_config: AudioConfig,
+  pub clients: Clients,
+  pub sequencer: Sequencer,
+  // What we last told the engine, so that we can tell clients
+  pub specs: Vec<UgenSpec>,
+  // The engine's id for the ugen made from each of specs
+  node_ids: Vec<NodeId>,
+  next_node_id: NodeId,
+  // How many ugens the last Reconfigure faded out
+  faded_out: usize,
+  pub control_blocks: NamedControlBlocks,
+  // Where each name lives in the engine's ControlBlocks. A name gets a
+  // slot when it's first set or used by a ugen.
+  slots: ControlSlots,
+  free_slots: Vec<usize>,
+  // Slots handed out so far, and how many the engine has room for
+  next_slot: usize,
+  engine_slots: usize,
+  pub lanes: NamedLanes,
+  // The engine's id for each of lanes, which changes when the lane does
+  lane_ids: HashMap<String, LaneId>,
+  next_lane_id: LaneId,
+  pub mod_routes: Vec<ModRoute>,
+  pub midi_bindings: Vec<MidiBinding>,
+  // What the next midi controller to move gets bound to
+  learning: Option<MidiTarget>,
+  pub wavetables: Wavetables,
+  commands: CommandProducer,
+  // Commands the engine's queue had no room for yet, oldest first
+  backlog: VecDeque<EngineCommand>,
+}
+
+pub type ControlGuard = Arc<Mutex<ControlState>>;
+
+impl ControlState {
+  pub fn new(
+    audio_config: AudioConfig,
+    wavetables: Wavetables,
+    commands: CommandProducer,
+  ) -> ControlState {
+    ControlState {
+      going: true,
+      audio_config,
+      clients: Clients::default(),
+      sequencer: Sequencer::new(),
+      specs: vec![],
+      node_ids: vec![],
+      next_node_id: 0,
+      faded_out: 0,
+      control_blocks: NamedControlBlocks::new(),
+      slots: ControlSlots::new(),
+      free_slots: vec![],
+      next_slot: 0,
+      engine_slots: INITIAL_CONTROL_BLOCKS,
+      lanes: NamedLanes::new(),
+      lane_ids: HashMap::new(),
+      next_lane_id: 0,
+      mod_routes: vec![],
+      midi_bindings: vec![],
+      learning: None,
+      wavetables,
+      commands,
+      backlog: VecDeque::new(),
+    }
+  }
+
+  // Commands are never dropped: when the engine's queue is full they
+  // wait in the backlog, and anything sent later waits behind them,
+  // since e.g. a SetControlBlock can need an earlier GrowControlBlocks.
+  pub fn send(&mut self, cmd: EngineCommand) {
+    self.backlog.push_back(cmd);
+    self.flush_commands();
+  }
+
+  // Moves as much of the backlog as there's room for onto the engine's
+  // queue. Called with every send, and regularly by whoever reads
+  // telemetry, so that the backlog drains even if nothing more is sent.
+  pub fn flush_commands(&mut self) {
+    while let Some(cmd) = self.backlog.pop_front() {
+      if let Err(PushError::Full(cmd)) = self.commands.push(cmd) {
+        self.backlog.push_front(cmd);
+        break;
+      }
+    }
+  }
+
+  pub fn send_to_clients(&mut self, msg: SynthMessage) {
+    self.clients.broadcast(msg);
+  }
+
+  pub fn send_to_client(&mut self, id: ClientId, msg: SynthMessage) {
+    self.clients.send(id, msg);
+  }
+
+  // Moves the engine to a new graph, which should already have been
+  // validated. Ugens whose specs haven't changed carry on as they are,
+  // so that sounding notes and effect tails survive; the rest fade in
+  // or out. We keep the specs in whatever order the client listed
+  // them, but the engine gets them in the order they need to run in.
+  pub fn reconfigure(&mut self, specs: Vec<UgenSpec>) -> Result<(), ValidationError> {
+    let config = self.audio_config;
+    for spec in specs.iter() {
+      if let Some((name, _)) = spec.control_block() {
+        self.slot(name);
+      }
+    }
+    let mut old: Vec<Option<(NodeId, UgenSpec)>> = self
+      .node_ids
+      .iter()
+      .copied()
+      .zip(self.specs.drain(..))
+      .map(Some)
+      .collect();
+
+    // A new spec takes over the first old ugen with the same spec
+    let mut node_ids = Vec::with_capacity(specs.len());
+    let mut kept = vec![false; specs.len()];
+    for (ix, spec) in specs.iter().enumerate() {
+      let same = old
+        .iter_mut()
+        .find(|o| matches!(o, Some((_, old_spec)) if old_spec == spec));
+      let id = match same.and_then(|o| o.take()) {
+        Some((id, _)) => {
+          kept[ix] = true;
+          id
+        },
+        None => {
+          self.next_node_id += 1;
+          self.next_node_id
+        },
+      };
+      node_ids.push(id);
+    }
+    let removed: Vec<(NodeId, UgenSpec)> = old.into_iter().flatten().collect();
+
+    // Ugens on their way out run where they would have, if that can be
+    // worked out, so that e.g. a reverb's tail still goes through
+    // whatever comes after it.
+    let all: Vec<UgenSpec> = specs
+      .iter()
+      .cloned()
+      .chain(removed.iter().map(|(_, spec)| spec.clone()))
+      .collect();
+    let order = match graph::execution_order(&all) {
+      Ok(order) => order,
+      Err(_) => {
+        let mut order = graph::execution_order(&specs)?;
+        order.extend(specs.len()..all.len());
+        order
+      },
+    };
+
+    let slots: Vec<Slot> = order
+      .into_iter()
+      .map(|ix| {
+        if ix >= specs.len() {
+          let (id, spec) = &removed[ix - specs.len()];
+          Slot::FadeOut(*id, Fade::new(spec.destinations(), config, false))
+        } else if kept[ix] {
+          Slot::Keep(node_ids[ix])
+        } else {
+          let spec = &specs[ix];
+          Slot::FadeIn(Node {
+            id: node_ids[ix],
+            ugen: Box::new(UgenState::new(
+              spec.clone(),
+              config.sample_rate_hz,
+              &self.slots,
+            )),
+            fade: Some(Fade::new(spec.destinations(), config, true)),
+          })
+        }
+      })
+      .collect();
+
+    // Room for what the last Reconfigure faded out, in case it's still
+    // fading out
+    let ugens = Vec::with_capacity(slots.len() + self.faded_out);
+    self.faded_out = removed.len();
+    self.specs = specs;
+    self.node_ids = node_ids;
+    self.send(EngineCommand::Reconfigure { slots, ugens });
+
+    // Ugens fading out still read their control blocks for a while, so
+    // those slots are kept until next time.
+    let in_use: HashSet<String> = self
+      .specs
+      .iter()
+      .chain(removed.iter().map(|(_, spec)| spec))
+      .filter_map(|spec| spec.control_block())
+      .map(|(name, _)| name.to_string())
+      .collect();
+    let unused: Vec<String> = self
+      .slots
+      .keys()
+      .filter(|name| !in_use.contains(*name) && !self.control_blocks.contains_key(*name))
+      .cloned()
+      .collect();
+    for name in unused {
+      self.release_slot(&name);
+    }
+    Ok(())
+  }
+
+  // The engine slot for control block `name`, which it gets if it
+  // doesn't have one already.
+  fn slot(&mut self, name: &str) -> usize {
+    if let Some(&slot) = self.slots.get(name) {
+      return slot;
+    }
+    let slot = match self.free_slots.pop() {
+      Some(slot) => slot,
+      None => {
+        if self.next_slot == self.engine_slots {
+          self.engine_slots *= 2;
+          let control_blocks = new_control_blocks(self.engine_slots);
+          self.send(EngineCommand::GrowControlBlocks(control_blocks));
+        }
+        self.next_slot += 1;
+        self.next_slot - 1
+      },
+    };
+    self.slots.insert(name.to_string(), slot);
+    slot
+  }
+
+  fn release_slot(&mut self, name: &str) {
+    if let Some(index) = self.slots.remove(name) {
+      self.free_slots.push(index);
+      self.send(EngineCommand::ClearControlBlock { index });
+    }
+  }
+
+  // Should already have been validated
+  pub fn set_control_block(&mut self, name: String, ctl: ControlBlock) {
+    let index = self.slot(&name);
+    self.control_blocks.insert(name, ctl.clone());
+    self.send(EngineCommand::SetControlBlock { index, ctl });
+  }
+
+  // Should already have been validated, so that no ugen in the graph
+  // is using it. Ugens the last Reconfigure faded out, and drums still
+  // ringing, may be, so like theirs, its slot keeps the block until the
+  // next Reconfigure frees it.
+  pub fn delete_control_block(&mut self, name: &str) {
+    self.control_blocks.remove(name);
+  }
+
+  // Should already have been validated
+  pub fn set_lane(&mut self, name: String, lane: Lane) {
+    self.next_lane_id += 1;
+    self.lane_ids.insert(name.clone(), self.next_lane_id);
+    self.lanes.insert(name, lane);
+    self.send_automation();
+  }
+
+  pub fn delete_lane(&mut self, name: &str) {
+    if self.lanes.remove(name).is_some() {
+      self.lane_ids.remove(name);
+      self.send_automation();
+    }
+  }
+
+  // The engine gets every lane at once; the ones it already has carry
+  // on where they were.
+  fn send_automation(&mut self) {
+    let config = self.audio_config;
+    let automation = self
+      .lanes
+      .iter()
+      .map(|(name, lane)| {
+        let index = self.slots[&lane.block];
+        LaneState::new(self.lane_ids[name], lane, index, config)
+      })
+      .collect();
+    self.send(EngineCommand::SetAutomation(automation));
+  }
+
+  // Should already have been validated
+  pub fn set_mod_routes(&mut self, routes: Vec<ModRoute>) {
+    let modulation = Modulation::new(&routes, &self.slots);
+    self.mod_routes = routes;
+    self.send(EngineCommand::SetModulation(modulation));
+  }
+
+  // Should already have been validated
+  pub fn set_midi_bindings(&mut self, bindings: Vec<MidiBinding>) {
+    self.midi_bindings = bindings;
+  }
+
+  // Should already have been validated
+  pub fn learn(&mut self, target: MidiTarget) {
+    self.learning = Some(target);
+  }
+
+  pub fn cancel_learn(&mut self) {
+    self.learning = None;
+  }
+
+  // Every midi message goes to the engine through here, so that the
+  // controller being learned, and the ones already bound, move their
+  // targets here as well, where patches and clients see them.
+  pub fn send_midi(&mut self, msg: Message) {
+    self.learn_midi(&msg);
+    self.apply_midi_bindings(&msg);
+    self.send(EngineCommand::Midi(msg));
+  }
+
+  fn apply_midi_bindings(&mut self, msg: &Message) {
+    let Message::ControlChange {
+      controller, value, ..
+    } = *msg
+    else {
+      return;
+    };
+    let moved: Vec<(String, ControlBlock)> = self
+      .midi_bindings
+      .iter()
+      .filter(|binding| binding.controller == controller)
+      .filter_map(|MidiBinding { target, .. }| {
+        let ctl = self.control_blocks.get(&target.block)?;
+        let ctl = param::set_param(&target.block, ctl, &target.path, target.scaled(value));
+        // The block may have been replaced by one of a different kind,
+        // or the number be one its block won't take
+        let ctl = ctl.ok()?;
+        validate::check_control_block(&target.block, &ctl, &self.specs).ok()?;
+        Some((target.block.clone(), ctl))
+      })
+      .collect();
+    for (name, ctl) in moved {
+      self.set_control_block(name, ctl);
+    }
+  }
+
+  // Learning ignores the controllers that validate::check_midi_bindings
+  // would refuse.
+  fn learn_midi(&mut self, msg: &Message) {
+    let Message::ControlChange { controller, .. } = *msg else {
+      return;
+    };
+    let binding = MidiBinding {
+      controller,
+      target: match self.learning.take() {
+        Some(target) => target,
+        None => return,
+      },
+    };
+    if validate::check_midi_bindings(std::slice::from_ref(&binding), &self.control_blocks).is_err()
+    {
+      self.learning = Some(binding.target);
+      return;
+    }
+    let mut bindings: Vec<MidiBinding> = self
+      .midi_bindings
+      .iter()
+      .filter(|b| b.target.block != binding.target.block || b.target.path != binding.target.path)
+      .cloned()
+      .collect();
+    bindings.push(binding.clone());
+    self.set_midi_bindings(bindings);
+    self.send_to_clients(SynthMessage::MidiLearned { binding });
+  }
+
+  pub fn patch(&self) -> Patch {
+    Patch {
+      specs: self.specs.clone(),
+      control_blocks: self.control_blocks.clone(),
+      lanes: self.lanes.clone(),
+      mod_routes: self.mod_routes.clone(),
+      midi_bindings: self.midi_bindings.clone(),
+    }
+  }
+
+  pub fn snapshot(&self) -> SynthMessage {
+    SynthMessage::State {
+      specs: self.specs.clone(),
+      control_blocks: self.control_blocks.clone(),
+      lanes: self.lanes.clone(),
+      mod_routes: self.mod_routes.clone(),
+      midi_bindings: self.midi_bindings.clone(),
+      sequencer: self.sequencer.tab().clone(),
+    }
+  }
+
+  // XXX move to midi manager somehow?
+  pub fn new_drum(&self, ctl: &str) -> Option<UgenState> {
+    let ci = *self.slots.get(ctl)?;
+    Some(crate::sequencer::new_drum(&self.wavetables, ci))
+  }
+}
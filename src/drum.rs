@@ -5,11 +5,13 @@ use ts_rs::TS;
 
 use crate::consts::BUS_DRY;
 use crate::envelope::{Adsr, EnvState};
+use crate::param::{self, Key, Params};
+use crate::smooth::{self, Smoothed};
 use crate::state::{ControlBlock, ControlBlocks, GenState};
 use crate::synth::TABLE_SIZE;
-use crate::{consts::SAMPLE_RATE_hz, ugen::Ugen};
+use crate::ugen::Ugen;
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Clone, Serialize, Deserialize, Debug, TS)]
 #[ts(export)]
 pub struct DrumControlBlock {
   pub vol: f32,
@@ -18,6 +20,18 @@ pub struct DrumControlBlock {
   pub adsr: Adsr,
 }
 
+impl Params for DrumControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("vol", []) => Some(&mut self.vol),
+      ("freq_hz", []) => Some(&mut self.freq_hz),
+      ("freq2_hz", []) => Some(&mut self.freq2_hz),
+      ("adsr", rest) => self.adsr.param_mut(rest),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct DrumSynthState {
   dst: usize,
@@ -26,6 +40,9 @@ pub struct DrumSynthState {
   env_state: EnvState,
   wavetable: Arc<Vec<f32>>,
   ci: usize,
+  vol: Smoothed,
+  freq_hz: Smoothed,
+  freq2_hz: Smoothed,
 }
 
 impl DrumSynthState {
@@ -42,12 +59,24 @@ impl DrumSynthState {
       },
       wavetable,
       ci,
+      vol: Smoothed::new(),
+      freq_hz: Smoothed::new(),
+      freq2_hz: Smoothed::new(),
     }
   }
 
   fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &DrumControlBlock) -> bool {
     let DrumControlBlock { adsr, .. } = ctl;
-    for out in gen.audio_bus[self.dst].iter_mut() {
+    let k = smooth::coefficient(tick_s, gen.ramp_s);
+    let vol_target = gen.target(self.ci, &["vol"], ctl.vol);
+    let freq_target = gen.target(self.ci, &["freq_hz"], ctl.freq_hz);
+    let freq2_target = gen.target(self.ci, &["freq2_hz"], ctl.freq2_hz);
+    let bus = &mut gen.audio_bus[self.dst];
+    for bus_ix in 0..bus[0].len() {
+      let vol = self.vol.follow(&vol_target, bus_ix, k);
+      let freq_hz = self.freq_hz.follow(&freq_target, bus_ix, k);
+      let freq2_hz = self.freq2_hz.follow(&freq2_target, bus_ix, k);
+
       let table_phase: f32 = self.phase * ((self.wavetable.len() - 1) as f32);
       let offset = table_phase.floor() as usize;
 
@@ -56,13 +85,16 @@ impl DrumSynthState {
       // linear interp
       let table_val = fpart * self.wavetable[offset + 1] + (1.0 - fpart) * self.wavetable[offset];
 
-      *out += 0.15 * table_val * self.env_state.amp(adsr) * ctl.vol;
+      let val = 0.15 * table_val * self.env_state.amp(adsr) * vol;
+      for line in bus.iter_mut() {
+        line[bus_ix] += val;
+      }
 
       // advance
       let a = self.env_state.time_s(adsr) / adsr.attack_len_s();
-      let eff_freq_hz = a * ctl.freq2_hz + (1.0 - a) * ctl.freq_hz;
+      let eff_freq_hz = a * freq2_hz + (1.0 - a) * freq_hz;
       let drum_freq_hz: f32 = eff_freq_hz / (TABLE_SIZE as f32);
-      self.phase += drum_freq_hz / SAMPLE_RATE_hz;
+      self.phase += drum_freq_hz * tick_s;
       if self.phase > 1. {
         self.phase -= 1.;
       }
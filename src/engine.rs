@@ -0,0 +1,791 @@
+use std::mem;
+
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::audio::AudioConfig;
+use crate::automation::{self, Automation};
+use crate::fade::Fade;
+use crate::midi::Message;
+use crate::modulation::{self, Modulation};
+use crate::reduce;
+use crate::state::{ControlBlock, ControlBlocks, State};
+use crate::synth::Synth;
+use crate::ugen::{Node, NodeId, UgenState, UgensState};
+use crate::webserver::SynthMessage;
+
+const COMMAND_QUEUE_CAPACITY: usize = 1024;
+const TELEMETRY_QUEUE_CAPACITY: usize = 1024;
+
+// Requests from the rest of the world to the audio thread. Anything
+// that needs allocating (e.g. new ugens) is built before it's sent,
+// so that the audio thread only has to move it into place.
+#[derive(Debug)]
+pub enum EngineCommand {
+  Midi(Message),
+  // Replace the ugen graph. `ugens` is empty, with room for the new
+  // graph, so that building it doesn't allocate.
+  Reconfigure { slots: Vec<Slot>, ugens: UgensState },
+  SetControlBlock { index: usize, ctl: ControlBlock },
+  ClearControlBlock { index: usize },
+  // Room for more control blocks: a vector of empty slots, longer than
+  // the current one
+  GrowControlBlocks(ControlBlocks),
+  // How long smoothed parameters take to follow a change, in seconds
+  SetParamRamp(f32),
+  // Replace every automation lane
+  SetAutomation(Automation),
+  // Replace the modulation matrix
+  SetModulation(Modulation),
+  // The sequencer is starting its pattern again
+  PatternStart,
+  // Add a ugen, e.g. a drum hit, to the first ugen group
+  AddToGroup(Box<UgenState>),
+  Quit,
+}
+
+// Where each ugen of a new graph comes from, in execution order.
+#[derive(Debug)]
+pub enum Slot {
+  // A ugen that's already running, left as it is
+  Keep(NodeId),
+  // A ugen that's already running, but isn't in the new graph
+  FadeOut(NodeId, Fade),
+  FadeIn(Node),
+}
+
+// Messages from the audio thread back to the rest of the world.
+#[derive(Debug)]
+pub enum Telemetry {
+  // To be forwarded to the web client
+  Synth(SynthMessage),
+  Warning(Warning),
+  // Things the audio thread is done with, sent back so that freeing
+  // them happens on some other thread.
+  Garbage(Garbage),
+}
+
+// Things that went wrong on the audio thread. They're Copy so that
+// reporting one doesn't allocate; the control side formats them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warning {
+  NoNote { pitch: u8, channel: u8 },
+  NoMidiManager { channel: u8 },
+  NoteAlreadyOff { pitch: u8, channel: u8 },
+  NoUgenGroup,
+  // A control block slot past the end of ControlBlocks
+  NoControlBlockSlot { index: usize },
+}
+
+impl std::fmt::Display for Warning {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Warning::NoNote { pitch, channel } => write!(f, "no note {pitch} on channel {channel}"),
+      Warning::NoMidiManager { channel } => {
+        write!(f, "couldn't find midi manager for channel {channel}")
+      },
+      Warning::NoteAlreadyOff { pitch, channel } => {
+        write!(
+          f,
+          "NoteOff {pitch} on channel {channel} on a ugen already off"
+        )
+      },
+      Warning::NoUgenGroup => write!(f, "couldn't find ugen group"),
+      Warning::NoControlBlockSlot { index } => write!(f, "no control block slot {index}"),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum Garbage {
+  Ugens(UgensState),
+  Slots(Vec<Slot>),
+  Node(Node),
+  Fade(Fade),
+  Ugen(Box<UgenState>),
+  ControlBlock(ControlBlock),
+  ControlBlocks(ControlBlocks),
+  Automation(Automation),
+  Modulation(Modulation),
+}
+
+pub type CommandProducer = Producer<EngineCommand>;
+pub type TelemetryProducer = Producer<Telemetry>;
+pub type TelemetryConsumer = Consumer<Telemetry>;
+
+// Owns everything the audio thread touches. Nothing in here is
+// shared; the only way in is the command queue, and the only way out
+// is the telemetry queue, both of which are wait-free.
+pub struct Engine {
+  pub state: State,
+  synth: Synth,
+  commands: Consumer<EngineCommand>,
+}
+
+impl Engine {
+  pub fn new(audio_config: AudioConfig) -> (Engine, CommandProducer, TelemetryConsumer) {
+    let (command_tx, command_rx) = RingBuffer::new(COMMAND_QUEUE_CAPACITY);
+    let (telemetry_tx, telemetry_rx) = RingBuffer::new(TELEMETRY_QUEUE_CAPACITY);
+    let engine = Engine {
+      state: State::new(audio_config, telemetry_tx),
+      synth: Synth::new(),
+      commands: command_rx,
+    };
+    (engine, command_tx, telemetry_rx)
+  }
+
+  // Applies every command that has arrived so far. Never blocks.
+  pub fn process_commands(&mut self) {
+    while let Ok(cmd) = self.commands.pop() {
+      self.apply(cmd);
+    }
+  }
+
+  fn apply(&mut self, cmd: EngineCommand) {
+    let s = &mut self.state;
+    match cmd {
+      EngineCommand::Midi(msg) => {
+        if let Err(w) = reduce::midi_reducer(&msg, s) {
+          s.send_telemetry(Telemetry::Warning(w));
+        }
+      },
+      EngineCommand::Reconfigure { mut slots, ugens } => {
+        let mut old = mem::replace(&mut s.fixed_ugens, ugens);
+        for slot in slots.drain(..) {
+          match slot {
+            Slot::Keep(id) => {
+              if let Some(node) = take_node(&mut old, id) {
+                push_node(s, node);
+              }
+            },
+            Slot::FadeOut(id, fade) => match take_node(&mut old, id) {
+              Some(mut node) => {
+                if let Some(old_fade) = node.fade.replace(fade) {
+                  s.send_telemetry(Telemetry::Garbage(Garbage::Fade(old_fade)));
+                }
+                push_node(s, node);
+              },
+              None => s.send_telemetry(Telemetry::Garbage(Garbage::Fade(fade))),
+            },
+            Slot::FadeIn(node) => push_node(s, node),
+          }
+        }
+        // Anything left is still fading out from an earlier
+        // Reconfigure, so it runs last, after the new graph.
+        for node in old.drain(..) {
+          push_node(s, node);
+        }
+        s.send_telemetry(Telemetry::Garbage(Garbage::Ugens(old)));
+        s.send_telemetry(Telemetry::Garbage(Garbage::Slots(slots)));
+      },
+      EngineCommand::SetControlBlock { index, ctl } => match s.control_blocks.get_mut(index) {
+        Some(slot) => {
+          if let Some(old) = slot.replace(ctl) {
+            s.send_telemetry(Telemetry::Garbage(Garbage::ControlBlock(old)));
+          }
+        },
+        None => {
+          s.send_telemetry(Telemetry::Garbage(Garbage::ControlBlock(ctl)));
+          s.send_telemetry(Telemetry::Warning(Warning::NoControlBlockSlot { index }));
+        },
+      },
+      EngineCommand::ClearControlBlock { index } => match s.control_blocks.get_mut(index) {
+        Some(slot) => {
+          if let Some(old) = slot.take() {
+            s.send_telemetry(Telemetry::Garbage(Garbage::ControlBlock(old)));
+          }
+        },
+        None => s.send_telemetry(Telemetry::Warning(Warning::NoControlBlockSlot { index })),
+      },
+      EngineCommand::GrowControlBlocks(mut control_blocks) => {
+        for (slot, ctl) in control_blocks.iter_mut().zip(s.control_blocks.iter_mut()) {
+          *slot = ctl.take();
+        }
+        let old = mem::replace(&mut s.control_blocks, control_blocks);
+        s.send_telemetry(Telemetry::Garbage(Garbage::ControlBlocks(old)));
+      },
+      EngineCommand::SetParamRamp(ramp_s) => {
+        s.param_ramp_s = ramp_s;
+      },
+      EngineCommand::SetAutomation(mut lanes) => {
+        automation::carry_over(&mut lanes, &s.automation, s.frame);
+        let old = mem::replace(&mut s.automation, lanes);
+        s.send_telemetry(Telemetry::Garbage(Garbage::Automation(old)));
+      },
+      EngineCommand::SetModulation(modulation) => {
+        s.modulation.restore(&mut s.control_blocks);
+        let old = mem::replace(&mut s.modulation, modulation);
+        s.send_telemetry(Telemetry::Garbage(Garbage::Modulation(old)));
+      },
+      EngineCommand::PatternStart => {
+        automation::restart_loops(s);
+        for node in s.fixed_ugens.iter_mut() {
+          if let UgenState::Lfo(lfo) = &mut *node.ugen {
+            lfo.pattern_start(&s.control_blocks);
+          }
+        }
+      },
+      EngineCommand::AddToGroup(ugen) => {
+        let maybe_group = s
+          .fixed_ugens
+          .iter_mut()
+          .filter(|node| !node.fading_out())
+          .find_map(|node| match &mut *node.ugen {
+            UgenState::UgenGroup(group) => Some(group),
+            _ => None,
+          });
+        if let Some(group) = maybe_group {
+          group.add(*ugen);
+        } else {
+          s.send_telemetry(Telemetry::Garbage(Garbage::Ugen(ugen)));
+          s.send_telemetry(Telemetry::Warning(Warning::NoUgenGroup));
+        }
+      },
+      EngineCommand::Quit => {
+        s.going = false;
+      },
+    }
+  }
+
+  pub fn going(&self) -> bool {
+    self.state.going
+  }
+
+  // Fills the audio busses with the next period of audio.
+  pub fn render(&mut self) {
+    automation::run(&mut self.state);
+    modulation::run(&mut self.state);
+    self.synth.synth_buf(&mut self.state);
+    self.state.frame += self.state.audio_config.period_frames as u64;
+  }
+}
+
+fn take_node(ugens: &mut UgensState, id: NodeId) -> Option<Node> {
+  let pos = ugens.iter().position(|node| node.id == id)?;
+  Some(ugens.swap_remove(pos))
+}
+
+// Only ever pushes into capacity we already have. If there isn't
+// enough, which can happen if Reconfigures arrive faster than ugens
+// fade out, the ugen is dropped abruptly.
+fn push_node(s: &mut State, node: Node) {
+  if s.fixed_ugens.len() < s.fixed_ugens.capacity() {
+    s.fixed_ugens.push(node);
+  } else {
+    s.send_telemetry(Telemetry::Garbage(Garbage::Node(node)));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    Engine, EngineCommand, Telemetry, TelemetryConsumer, Warning, COMMAND_QUEUE_CAPACITY,
+  };
+  use crate::audio::AudioConfig;
+  use crate::automation::{Breakpoint, Curve, Lane};
+  use crate::consts::BUS_OUT;
+  use crate::control::ControlState;
+  use crate::envelope::Adsr;
+  use crate::gain::GainControlBlock;
+  use crate::midi::Message;
+  use crate::midi_learn::MidiTarget;
+  use crate::midi_manager::{MidiManagerState, Mono, Polyphony, Priority, Steal};
+  use crate::reasonable_synth::{ReasonableControlBlock, Wheels};
+  use crate::state::{ControlBlock, INITIAL_CONTROL_BLOCKS};
+  use crate::ugen::{UgenSpec, UgenState};
+
+  const CONFIG: AudioConfig = AudioConfig {
+    sample_rate_hz: 44100,
+    period_frames: 64,
+  };
+
+  fn out_level(engine: &Engine) -> f32 {
+    engine.state.audio_bus[BUS_OUT]
+      .iter()
+      .flat_map(|line| line.iter())
+      .fold(0.0, |acc, x| acc.max(x.abs()))
+  }
+
+  fn manager_spec() -> UgenSpec {
+    UgenSpec::MidiManager {
+      dst: BUS_OUT,
+      ci: "synth".to_string(),
+      channels: None,
+    }
+  }
+
+  fn reasonable() -> ControlBlock {
+    ControlBlock::Reasonable(ReasonableControlBlock {
+      adsr: Adsr {
+        attack_s: 0.001,
+        decay_s: 0.1,
+        sustain: 0.5,
+        release_s: 0.1,
+      },
+      wheels: Wheels::default(),
+      polyphony: Polyphony::default(),
+      mono: None,
+      tuning: None,
+    })
+  }
+
+  fn new_engine() -> (Engine, ControlState, TelemetryConsumer) {
+    let (engine, commands, telemetry) = Engine::new(CONFIG);
+    let control = ControlState::new(CONFIG, engine.state.wavetables.clone(), commands);
+    (engine, control, telemetry)
+  }
+
+  // A midi manager on the output, playing control block "synth"
+  fn playing_engine() -> (Engine, ControlState, TelemetryConsumer) {
+    let (engine, mut control, telemetry) = new_engine();
+    control.reconfigure(vec![manager_spec()]).unwrap();
+    control.set_control_block("synth".to_string(), reasonable());
+    (engine, control, telemetry)
+  }
+
+  fn gain_block() -> ControlBlock {
+    ControlBlock::Gain(GainControlBlock { scale: 1.0 })
+  }
+
+  // The scale the engine's gain block has now
+  fn gain_scale(engine: &Engine) -> f32 {
+    let scale = engine
+      .state
+      .control_blocks
+      .iter()
+      .flatten()
+      .find_map(|ctl| match ctl {
+        ControlBlock::Gain(ctl) => Some(ctl.scale),
+        _ => None,
+      });
+    scale.expect("no gain block")
+  }
+
+  fn breakpoint(time_s: f32, value: f32) -> Breakpoint {
+    Breakpoint {
+      time_s,
+      value,
+      curve: Curve::Linear,
+    }
+  }
+
+  fn on(pitch: u8, channel: u8) -> EngineCommand {
+    EngineCommand::Midi(Message::NoteOn {
+      pitch,
+      channel,
+      velocity: 100,
+    })
+  }
+
+  fn off(pitch: u8, channel: u8) -> EngineCommand {
+    EngineCommand::Midi(Message::NoteOff { pitch, channel })
+  }
+
+  // The midi manager playing_engine puts first
+  fn manager(engine: &Engine) -> &MidiManagerState {
+    match &*engine.state.fixed_ugens[0].ugen {
+      UgenState::MidiManager(m) => m,
+      _ => panic!("no midi manager"),
+    }
+  }
+
+  fn notes_playing(engine: &Engine) -> usize {
+    manager(engine).notegen_state.iter().flatten().count()
+  }
+
+  #[test]
+  fn commands_reach_the_audio_thread() {
+    let (mut engine, mut control, mut telemetry) = playing_engine();
+    control.send(on(60, 0));
+
+    engine.render();
+    assert_eq!(out_level(&engine), 0.0);
+
+    engine.process_commands();
+    engine.render();
+    engine.render();
+    assert!(out_level(&engine) > 0.0);
+
+    // the empty graph we started with comes back to be freed elsewhere
+    assert!(matches!(telemetry.pop(), Ok(Telemetry::Garbage(_))));
+
+    control.send(EngineCommand::Quit);
+    engine.process_commands();
+    assert!(!engine.going());
+  }
+
+  #[test]
+  fn reconfigure_keeps_unchanged_ugens() {
+    let (mut engine, mut control, _telemetry) = playing_engine();
+    control.send(on(60, 0));
+    engine.process_commands();
+    engine.render();
+    assert_eq!(notes_playing(&engine), 1);
+
+    let meter = UgenSpec::Meter { src: BUS_OUT };
+    control.reconfigure(vec![manager_spec(), meter]).unwrap();
+    engine.process_commands();
+    engine.render();
+    assert_eq!(engine.state.fixed_ugens.len(), 2);
+    assert_eq!(notes_playing(&engine), 1);
+    assert!(engine.state.fixed_ugens[1].fade.is_some());
+
+    // The meter finishes fading in, and then a replaced manager fades
+    // out, and is gone once it has.
+    for _ in 0..100 {
+      engine.render();
+    }
+    assert!(engine.state.fixed_ugens[1].fade.is_none());
+    let moved = UgenSpec::MidiManager {
+      dst: 2,
+      ci: "synth".to_string(),
+      channels: None,
+    };
+    control.reconfigure(vec![moved]).unwrap();
+    engine.process_commands();
+    engine.render();
+    assert_eq!(engine.state.fixed_ugens.len(), 3);
+    for _ in 0..100 {
+      engine.render();
+    }
+    assert_eq!(engine.state.fixed_ugens.len(), 1);
+  }
+
+  #[test]
+  fn control_blocks_are_made_on_demand() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    let n = 2 * INITIAL_CONTROL_BLOCKS + 1;
+    for i in 0..n {
+      control.set_control_block(format!("synth{i}"), reasonable());
+    }
+    engine.process_commands();
+    let count = |engine: &Engine| engine.state.control_blocks.iter().flatten().count();
+    assert_eq!(count(&engine), n);
+
+    control.delete_control_block("synth0");
+    control.set_control_block("other".to_string(), reasonable());
+    engine.process_commands();
+    assert!(control.control_blocks.contains_key("other"));
+    assert!(!control.control_blocks.contains_key("synth0"));
+    // synth0 stays in its slot, for anything still fading out, until
+    // the next reconfigure
+    assert_eq!(count(&engine), n + 1);
+    control.reconfigure(vec![]).unwrap();
+    engine.process_commands();
+    assert_eq!(count(&engine), n);
+  }
+
+  #[test]
+  fn a_full_queue_delays_commands() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    for _ in 0..COMMAND_QUEUE_CAPACITY {
+      control.send(EngineCommand::SetParamRamp(0.0));
+    }
+    // the last of these needs the engine to grow its control blocks
+    let n = INITIAL_CONTROL_BLOCKS + 1;
+    for i in 0..n {
+      control.set_control_block(format!("synth{i}"), reasonable());
+    }
+    engine.process_commands();
+    assert_eq!(engine.state.control_blocks.iter().flatten().count(), 0);
+    control.flush_commands();
+    engine.process_commands();
+    assert_eq!(engine.state.control_blocks.iter().flatten().count(), n);
+  }
+
+  #[test]
+  fn missing_control_block_slots_are_reported() {
+    let (mut engine, mut control, mut telemetry) = new_engine();
+    let index = INITIAL_CONTROL_BLOCKS;
+    control.send(EngineCommand::ClearControlBlock { index });
+    engine.process_commands();
+    assert!(matches!(
+      telemetry.pop(),
+      Ok(Telemetry::Warning(Warning::NoControlBlockSlot { index: i })) if i == index
+    ));
+  }
+
+  #[test]
+  fn automation_runs_on_the_sample_clock() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    control.set_control_block("fx".to_string(), gain_block());
+    let lane = Lane {
+      block: "fx".to_string(),
+      path: "scale".to_string(),
+      points: vec![breakpoint(0.0, 0.0), breakpoint(1.0, 1.0)],
+      looped: true,
+    };
+    control.set_lane("sweep".to_string(), lane);
+
+    engine.process_commands();
+    engine.render();
+    assert_eq!(gain_scale(&engine), 0.0);
+    // half a second in; the last period started 344 periods in
+    for _ in 0..344 {
+      engine.render();
+    }
+    let expected = (344 * CONFIG.period_frames) as f32 / CONFIG.sample_rate_hz as f32;
+    assert!((gain_scale(&engine) - expected).abs() < 1e-5);
+
+    // a looped lane starts over with the sequencer's pattern
+    control.send(EngineCommand::PatternStart);
+    engine.process_commands();
+    engine.render();
+    assert_eq!(gain_scale(&engine), 0.0);
+  }
+
+  #[test]
+  fn automation_is_sample_accurate() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    let synth = UgenSpec::MidiManager {
+      dst: 2,
+      ci: "synth".to_string(),
+      channels: None,
+    };
+    let fx = UgenSpec::Gain {
+      src: 2,
+      dst: BUS_OUT,
+      ci: "fx".to_string(),
+      channels: None,
+    };
+    control.reconfigure(vec![synth, fx]).unwrap();
+    control.set_control_block("synth".to_string(), reasonable());
+    control.set_control_block("fx".to_string(), gain_block());
+    // a step from 0 to 1 partway through a period, once the graph has
+    // faded in
+    let periods = 100;
+    let step = 36;
+    let step_s = (periods * CONFIG.period_frames + step) as f32 / CONFIG.sample_rate_hz as f32;
+    let lane = Lane {
+      block: "fx".to_string(),
+      path: "scale".to_string(),
+      points: vec![
+        breakpoint(0.0, 0.0),
+        breakpoint(step_s, 0.0),
+        breakpoint(step_s, 1.0),
+      ],
+      looped: false,
+    };
+    control.set_lane("step".to_string(), lane);
+    control.send(on(60, 0));
+
+    engine.process_commands();
+    for _ in 0..=periods {
+      engine.render();
+    }
+    let src = &engine.state.audio_bus[2][0];
+    let out = &engine.state.audio_bus[BUS_OUT][0];
+    assert!(out[..step].iter().all(|x| *x == 0.0));
+    assert_eq!(out[step..], src[step..]);
+    assert!(out[step..].iter().any(|x| *x != 0.0));
+  }
+
+  #[test]
+  fn wheels_reach_the_midi_manager() {
+    let (mut engine, mut control, _telemetry) = playing_engine();
+    control.send(on(60, 0));
+    control.send(EngineCommand::Midi(Message::PitchBend {
+      channel: 0,
+      value: -8192,
+    }));
+    control.send(EngineCommand::Midi(Message::ControlChange {
+      channel: 0,
+      controller: 1,
+      value: 127,
+    }));
+    engine.process_commands();
+    engine.render();
+    assert_eq!(manager(&engine).bend, -1.0);
+    assert_eq!(manager(&engine).mod_wheel, 1.0);
+    assert_eq!(notes_playing(&engine), 1);
+    assert_eq!(engine.state.midi_cc[1], 1.0);
+  }
+
+  #[test]
+  fn midi_learn_binds_the_next_controller() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    control.set_control_block("fx".to_string(), gain_block());
+    let target = MidiTarget {
+      block: "fx".to_string(),
+      path: "scale".to_string(),
+      min: 0.0,
+      max: 2.0,
+      curve: Curve::Linear,
+    };
+    control.learn(target.clone());
+    let cc = |controller, value| Message::ControlChange {
+      channel: 3,
+      controller,
+      value,
+    };
+    // the sustain pedal can't be learned
+    for msg in [cc(64, 127), cc(74, 127), cc(75, 0)] {
+      control.send_midi(msg);
+    }
+    assert_eq!(control.midi_bindings.len(), 1);
+    assert_eq!(control.midi_bindings[0].controller, 74);
+    assert_eq!(control.midi_bindings[0].target, target);
+    engine.process_commands();
+    assert_eq!(gain_scale(&engine), 2.0);
+
+    control.send_midi(cc(74, 0));
+    engine.process_commands();
+    assert_eq!(gain_scale(&engine), 0.0);
+    // so that patches, and anything else setting the block, keep it
+    match &control.control_blocks["fx"] {
+      ControlBlock::Gain(ctl) => assert_eq!(ctl.scale, 0.0),
+      _ => panic!("no gain block"),
+    }
+  }
+
+  #[test]
+  fn channels_play_their_own_managers() {
+    let (mut engine, mut control, _telemetry) = new_engine();
+    let spec = |ci: &str, dst, channels| UgenSpec::MidiManager {
+      dst,
+      ci: ci.to_string(),
+      channels: Some(channels),
+    };
+    control
+      .reconfigure(vec![
+        spec("lead", BUS_OUT, vec![0]),
+        spec("bass", 2, vec![1, 9]),
+      ])
+      .unwrap();
+    control.set_control_block("lead".to_string(), reasonable());
+    control.set_control_block("bass".to_string(), reasonable());
+    for channel in [1, 9, 9, 3] {
+      control.send(on(40 + channel, channel));
+    }
+    engine.process_commands();
+    let mut playing: Vec<(usize, usize)> = engine
+      .state
+      .fixed_ugens
+      .iter()
+      .filter_map(|node| match &*node.ugen {
+        UgenState::MidiManager(m) => Some((m.dst, m.notegen_state.iter().flatten().count())),
+        _ => None,
+      })
+      .collect();
+    playing.sort();
+    // nothing plays channel 3
+    assert_eq!(playing, vec![(BUS_OUT, 0), (2, 2)]);
+  }
+
+  #[test]
+  fn voices_are_stolen_past_the_limit() {
+    let (mut engine, mut control, _telemetry) = playing_engine();
+    let mut ctl = reasonable();
+    if let ControlBlock::Reasonable(ctl) = &mut ctl {
+      ctl.polyphony = Polyphony {
+        voices: 2,
+        steal: Steal::SameNote,
+      };
+    }
+    control.set_control_block("synth".to_string(), ctl);
+    for cmd in [on(60, 0), on(62, 0), off(60, 0), on(60, 0), on(64, 0)] {
+      control.send(cmd);
+    }
+    engine.process_commands();
+    let pitches = |engine: &Engine| {
+      let mut pitches: Vec<(u8, bool)> = manager(engine)
+        .notegen_state
+        .iter()
+        .flatten()
+        .map(|n| (n.pitch, n.stolen()))
+        .collect();
+      pitches.sort();
+      pitches
+    };
+    // the released 60 made room for the new one, and then 62, the
+    // oldest, for 64
+    assert_eq!(
+      pitches(&engine),
+      vec![(60, false), (60, true), (62, true), (64, false)]
+    );
+    for _ in 0..10 {
+      engine.render();
+    }
+    assert_eq!(pitches(&engine), vec![(60, false), (64, false)]);
+  }
+
+  #[test]
+  fn mono_plays_one_note_at_a_time() {
+    let (mut engine, mut control, _telemetry) = playing_engine();
+    let mono = |priority| {
+      let mut ctl = reasonable();
+      if let ControlBlock::Reasonable(ctl) = &mut ctl {
+        ctl.mono = Some(Mono {
+          priority,
+          legato: true,
+          glide_s: 0.05,
+        });
+      }
+      ctl
+    };
+    control.set_control_block("synth".to_string(), mono(Priority::Last));
+    fn play(
+      control: &mut ControlState,
+      engine: &mut Engine,
+      cmds: Vec<EngineCommand>,
+    ) -> Vec<(u8, bool)> {
+      for cmd in cmds {
+        control.send(cmd);
+      }
+      engine.process_commands();
+      engine.render();
+      manager(engine)
+        .notegen_state
+        .iter()
+        .flatten()
+        .map(|n| (n.pitch, n.released()))
+        .collect()
+    }
+    assert_eq!(
+      play(&mut control, &mut engine, vec![on(60, 0), on(64, 0)]),
+      vec![(64, false)]
+    );
+    // back to the note still held
+    assert_eq!(
+      play(&mut control, &mut engine, vec![off(64, 0)]),
+      vec![(60, false)]
+    );
+    assert_eq!(
+      play(&mut control, &mut engine, vec![off(60, 0)]),
+      vec![(60, true)]
+    );
+    // a new note takes over the voice as it releases
+    assert_eq!(
+      play(&mut control, &mut engine, vec![on(67, 0)]),
+      vec![(67, false)]
+    );
+
+    control.set_control_block("synth".to_string(), mono(Priority::Low));
+    assert_eq!(
+      play(
+        &mut control,
+        &mut engine,
+        vec![on(72, 0), on(55, 0), on(60, 0)]
+      ),
+      vec![(55, false)]
+    );
+    assert_eq!(
+      play(&mut control, &mut engine, vec![off(55, 0)]),
+      vec![(60, false)]
+    );
+  }
+
+  #[test]
+  fn every_midi_note_plays() {
+    let (mut engine, mut control, _telemetry) = playing_engine();
+    // the same note on two channels is two notes
+    for cmd in [on(0, 0), on(127, 0), on(36, 9), on(36, 10), on(128, 0)] {
+      control.send(cmd);
+    }
+    engine.process_commands();
+    assert_eq!(notes_playing(&engine), 4);
+    control.send(off(36, 9));
+    engine.process_commands();
+    let notes = manager(&engine).notegen_state.iter().flatten();
+    assert_eq!(notes.filter(|n| n.released()).count(), 1);
+  }
+}
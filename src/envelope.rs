@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-#[derive(Clone, Serialize, Deserialize, Debug, TS)]
+use crate::param::{self, Key, Params};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, TS)]
 #[ts(export)]
 pub struct Adsr {
   pub attack_s: f32,
@@ -10,6 +12,18 @@ pub struct Adsr {
   pub release_s: f32,
 }
 
+impl Params for Adsr {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("attack_s", []) => Some(&mut self.attack_s),
+      ("decay_s", []) => Some(&mut self.decay_s),
+      ("sustain", []) => Some(&mut self.sustain),
+      ("release_s", []) => Some(&mut self.release_s),
+      _ => None,
+    }
+  }
+}
+
 // This is the part of the state that tracks where a note is in its
 // ADSR envelope.
 #[derive(Clone, Debug)]
@@ -0,0 +1,106 @@
+use crate::audio::AudioConfig;
+use crate::consts::BUS_CHANNELS;
+use crate::state::{AudioBus, AudioBusses};
+
+// How long ugens take to come and go when the graph is reconfigured
+const CROSSFADE_s: f32 = 0.05;
+
+// Fades a ugen in or out, without knowing anything about the ugen
+// except which busses it writes. Before the ugen runs we save those
+// busses, and afterwards mix between what was there before and what
+// the ugen left there. So a ugen that adds to a bus gets its
+// contribution scaled, and a filter that overwrites its input is
+// mixed with what it's filtering, which is what we want from either
+// when it's being added to or removed from the graph.
+#[derive(Debug)]
+pub struct Fade {
+  dsts: Vec<usize>,
+  // One saved bus for each of dsts
+  before: Vec<AudioBus>,
+  gain: f32,
+  // Per sample; negative when fading out
+  step: f32,
+}
+
+impl Fade {
+  pub fn new(mut dsts: Vec<usize>, audio_config: AudioConfig, fade_in: bool) -> Fade {
+    // Mixing the same bus twice would fade it twice over
+    dsts.sort();
+    dsts.dedup();
+    let bus = vec![vec![0.; audio_config.period_frames]; BUS_CHANNELS];
+    let step = audio_config.tick_s() / CROSSFADE_s;
+    Fade {
+      before: vec![bus; dsts.len()],
+      dsts,
+      gain: if fade_in { 0.0 } else { 1.0 },
+      step: if fade_in { step } else { -step },
+    }
+  }
+
+  pub fn fading_out(&self) -> bool {
+    self.step < 0.0
+  }
+
+  pub fn done(&self) -> bool {
+    if self.fading_out() {
+      self.gain <= 0.0
+    } else {
+      self.gain >= 1.0
+    }
+  }
+
+  // Call before running the ugen
+  pub fn save(&mut self, busses: &AudioBusses) {
+    for (&dst, saved) in self.dsts.iter().zip(self.before.iter_mut()) {
+      for (line, saved_line) in busses[dst].iter().zip(saved.iter_mut()) {
+        saved_line.copy_from_slice(line);
+      }
+    }
+  }
+
+  // Call after running the ugen. Advances the fade by one buffer.
+  pub fn mix(&mut self, busses: &mut AudioBusses) {
+    for (&dst, saved) in self.dsts.iter().zip(self.before.iter()) {
+      for (line, saved_line) in busses[dst].iter_mut().zip(saved.iter()) {
+        for (ix, (m, before)) in line.iter_mut().zip(saved_line.iter()).enumerate() {
+          let gain = (self.gain + self.step * (ix as f32)).clamp(0.0, 1.0);
+          *m = before + gain * (*m - before);
+        }
+      }
+    }
+    let len = busses[0][0].len();
+    self.gain = (self.gain + self.step * (len as f32)).clamp(0.0, 1.0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Fade;
+  use crate::audio::AudioConfig;
+
+  #[test]
+  fn fades_between_before_and_after() {
+    let config = AudioConfig {
+      sample_rate_hz: 100,
+      period_frames: 4,
+    };
+    // 0.05s at 100Hz is 5 samples
+    let mut fade = Fade::new(vec![0, 0], config, true);
+    let mut busses = vec![vec![vec![1.0; 4]; 2]];
+    fade.save(&busses);
+    busses[0][0] = vec![2.0; 4];
+    fade.mix(&mut busses);
+    let expected = [1.0, 1.2, 1.4, 1.6];
+    for (got, want) in busses[0][0].iter().zip(expected) {
+      assert!((got - want).abs() < 1e-5);
+    }
+    assert_eq!(busses[0][1], vec![1.0; 4]);
+    assert!(!fade.done());
+
+    fade.save(&busses);
+    busses[0][0] = vec![0.0; 4];
+    fade.mix(&mut busses);
+    assert!(fade.done());
+    assert_eq!(busses[0][0][3], 0.0);
+  }
+}
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::state::{ControlBlock, ControlBlocks, GenState};
+use crate::param::{self, Key, Params};
+use crate::smooth::{self, Smoothed};
+use crate::state::{
+  read_channel, ugen_channels, write_channel, ControlBlock, ControlBlocks, GenState,
+};
 use crate::ugen::Ugen;
 
 const LOW_PASS_AMOUNT: usize = 35000;
@@ -12,7 +16,7 @@ pub struct Tap {
   pub weight: f32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
 #[ts(export)]
@@ -20,21 +24,50 @@ pub struct GainControlBlock {
   pub scale: f32,
 }
 
+impl Params for GainControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("scale", []) => Some(&mut self.scale),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct GainState {
   src: usize,
   dst: usize,
   ci: usize,
+  channels: usize,
+  scale: Smoothed,
 }
 
 impl GainState {
-  pub fn new(src: usize, dst: usize, ci: usize) -> Self {
-    GainState { src, dst, ci }
+  pub fn new(src: usize, dst: usize, ci: usize, channels: Option<usize>) -> Self {
+    GainState {
+      src,
+      dst,
+      ci,
+      channels: ugen_channels(channels),
+      scale: Smoothed::new(),
+    }
   }
 
-  fn ctl_run(&mut self, gen: GenState, ctl: &GainControlBlock) -> bool {
-    for bus_ix in 0..gen.audio_bus[0].len() {
-      gen.audio_bus[self.dst][bus_ix] = gen.audio_bus[self.src][bus_ix] * ctl.scale;
+  fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &GainControlBlock) -> bool {
+    let k = smooth::coefficient(tick_s, gen.ramp_s);
+    let target = gen.target(self.ci, &["scale"], ctl.scale);
+    for bus_ix in 0..gen.audio_bus[0][0].len() {
+      let scale = self.scale.follow(&target, bus_ix, k);
+      for ch in 0..self.channels {
+        let val = read_channel(&gen.audio_bus[self.src], self.channels, ch, bus_ix);
+        write_channel(
+          &mut gen.audio_bus[self.dst],
+          self.channels,
+          ch,
+          bus_ix,
+          val * scale,
+        );
+      }
     }
     true
   }
@@ -43,7 +76,7 @@ impl GainState {
 impl Ugen for GainState {
   fn run(&mut self, gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool {
     match &ctl[self.ci] {
-      Some(ControlBlock::Gain(ctl)) => self.ctl_run(gen, &ctl),
+      Some(ControlBlock::Gain(ctl)) => self.ctl_run(gen, tick_s, &ctl),
       _ => false,
     }
   }
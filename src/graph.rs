@@ -0,0 +1,107 @@
+use crate::ugen::UgenSpec;
+use crate::validate::ValidationError;
+
+// The engine runs ugens one after another, each over a whole buffer,
+// so a ugen has to run after everything that writes the busses it
+// reads, or it hears the previous buffer, or silence.
+
+// Does ugen `i` have to run before ugen `j`? Ugens that both read
+// and write the same bus, e.g. a gain applied in place, form a chain
+// on that bus, which runs in the order they were listed.
+fn feeds(specs: &[UgenSpec], i: usize, j: usize) -> bool {
+  let (a, b) = (&specs[i], &specs[j]);
+  a.destinations().iter().any(|bus| {
+    b.sources().contains(bus)
+      && !(a.sources().contains(bus) && b.destinations().contains(bus) && j < i)
+  })
+}
+
+// An order to run `specs` in, as indices into `specs`, such that
+// every ugen runs after those that feed it. Otherwise ugens stay in
+// the order they were listed.
+pub fn execution_order(specs: &[UgenSpec]) -> Result<Vec<usize>, ValidationError> {
+  let n = specs.len();
+  let mut indegree = vec![0; n];
+  for i in 0..n {
+    for (j, deg) in indegree.iter_mut().enumerate() {
+      if i != j && feeds(specs, i, j) {
+        *deg += 1;
+      }
+    }
+  }
+
+  let mut order = Vec::with_capacity(n);
+  let mut done = vec![false; n];
+  while order.len() < n {
+    let Some(next) = (0..n).find(|&i| !done[i] && indegree[i] == 0) else {
+      let ugens = (0..n).filter(|&i| !done[i]).collect();
+      return Err(ValidationError::Cycle { ugens });
+    };
+    done[next] = true;
+    order.push(next);
+    for (j, deg) in indegree.iter_mut().enumerate() {
+      if j != next && feeds(specs, next, j) {
+        *deg -= 1;
+      }
+    }
+  }
+  Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::execution_order;
+  use crate::consts::BUS_OUT;
+  use crate::ugen::UgenSpec;
+  use crate::validate::ValidationError;
+
+  fn gain(src: usize, dst: usize) -> UgenSpec {
+    UgenSpec::Gain {
+      src,
+      dst,
+      ci: "gain".to_string(),
+      channels: None,
+    }
+  }
+
+  #[test]
+  fn readers_run_after_writers() {
+    let specs = vec![
+      gain(3, BUS_OUT),
+      UgenSpec::Reverb {
+        src: 2,
+        dst: 3,
+        ci: "reverb".to_string(),
+      },
+      UgenSpec::MidiManager {
+        dst: 2,
+        ci: "synth".to_string(),
+        channels: None,
+      },
+    ];
+    assert_eq!(execution_order(&specs), Ok(vec![2, 1, 0]));
+  }
+
+  #[test]
+  fn in_place_ugens_keep_their_order() {
+    let specs = vec![gain(4, 4), gain(4, 4), gain(5, 4)];
+    assert_eq!(execution_order(&specs), Ok(vec![2, 0, 1]));
+  }
+
+  #[test]
+  fn cycles_are_rejected() {
+    let specs = vec![
+      UgenSpec::MidiManager {
+        dst: 4,
+        ci: "synth".to_string(),
+        channels: None,
+      },
+      gain(4, 5),
+      gain(5, 4),
+    ];
+    assert_eq!(
+      execution_order(&specs),
+      Err(ValidationError::Cycle { ugens: vec![1, 2] })
+    );
+  }
+}
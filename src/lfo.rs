@@ -0,0 +1,147 @@
+use std::f32::consts::TAU;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::param::{self, Key, Params};
+use crate::sequencer::SEQ_STEP_MS;
+use crate::state::{ControlBlock, ControlBlocks, GenState};
+use crate::ugen::Ugen;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum LfoShape {
+  Sine,
+  Triangle,
+  // Rising
+  Saw,
+  Square,
+  // A new random value every cycle
+  SampleAndHold,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct LfoControlBlock {
+  pub shape: LfoShape,
+  pub rate_hz: f32,
+  // If set, the LFO ignores rate_hz and takes this many sequencer steps
+  // per cycle, starting each cycle in time with the pattern
+  #[serde(default)]
+  #[ts(optional = nullable)]
+  pub sync_steps: Option<f32>,
+}
+
+impl Params for LfoControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("rate_hz", []) => Some(&mut self.rate_hz),
+      ("sync_steps", []) => self.sync_steps.as_mut(),
+      _ => None,
+    }
+  }
+}
+
+// A modulation source. It makes no sound; once a period it works out
+// a value between -1 and 1, which the modulation matrix reads. See
+// modulation.rs.
+#[derive(Clone, Debug)]
+pub struct LfoState {
+  pub ci: usize,
+  // In cycles, from 0 to 1
+  phase: f32,
+  value: f32,
+  held: f32,
+  // Seeded, so that renders come out the same every time
+  rng: StdRng,
+}
+
+impl LfoState {
+  pub fn new(ci: usize) -> Self {
+    let mut rng = StdRng::seed_from_u64(ci as u64);
+    LfoState {
+      ci,
+      phase: 0.0,
+      value: 0.0,
+      held: rng.gen_range(-1.0..=1.0),
+      rng,
+    }
+  }
+
+  pub fn value(&self) -> f32 {
+    self.value
+  }
+
+  // Synced LFOs start their cycle again with the sequencer's pattern
+  pub fn pattern_start(&mut self, ctl: &ControlBlocks) {
+    if let Some(ControlBlock::Lfo(LfoControlBlock {
+      sync_steps: Some(_),
+      ..
+    })) = &ctl[self.ci]
+    {
+      self.phase = 0.0;
+    }
+  }
+
+  fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &LfoControlBlock) -> bool {
+    self.value = shape_value(ctl.shape, self.phase, self.held);
+    let rate_hz = match ctl.sync_steps {
+      Some(steps) => 1000.0 / (steps * SEQ_STEP_MS as f32),
+      None => ctl.rate_hz,
+    };
+    let period_s = tick_s * gen.audio_bus[0][0].len() as f32;
+    self.phase += rate_hz * period_s;
+    if !self.phase.is_finite() || self.phase < 0.0 {
+      self.phase = 0.0;
+    }
+    if self.phase >= 1.0 {
+      self.phase = self.phase.fract();
+      self.held = self.rng.gen_range(-1.0..=1.0);
+    }
+    true
+  }
+}
+
+fn shape_value(shape: LfoShape, phase: f32, held: f32) -> f32 {
+  match shape {
+    LfoShape::Sine => (TAU * phase).sin(),
+    LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+    LfoShape::Saw => 2.0 * phase - 1.0,
+    LfoShape::Square => {
+      if phase < 0.5 {
+        1.0
+      } else {
+        -1.0
+      }
+    },
+    LfoShape::SampleAndHold => held,
+  }
+}
+
+impl Ugen for LfoState {
+  fn run(&mut self, gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool {
+    match &ctl[self.ci] {
+      Some(ControlBlock::Lfo(ctl)) => self.ctl_run(gen, tick_s, ctl),
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{shape_value, LfoShape};
+
+  #[test]
+  fn shapes_span_minus_one_to_one() {
+    for (phase, want) in [(0.0, -1.0), (0.25, 0.0), (0.5, 1.0), (0.75, 0.0)] {
+      assert!((shape_value(LfoShape::Triangle, phase, 0.0) - want).abs() < 1e-6);
+    }
+    assert_eq!(shape_value(LfoShape::Saw, 0.0, 0.0), -1.0);
+    assert_eq!(shape_value(LfoShape::Square, 0.75, 0.0), -1.0);
+    assert!((shape_value(LfoShape::Sine, 0.25, 0.0) - 1.0).abs() < 1e-6);
+    assert_eq!(shape_value(LfoShape::SampleAndHold, 0.3, 0.7), 0.7);
+  }
+}
@@ -1,12 +1,15 @@
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::state::{ControlBlock, ControlBlocks, GenState};
+use crate::param::{self, Key, Params};
+use crate::state::{
+  read_channel, ugen_channels, write_channel, ControlBlock, ControlBlocks, GenState,
+};
 use crate::ugen::Ugen;
 
 const HISTORY_SIZE: usize = 35000;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "t")]
 #[derive(TS)]
 #[ts(export)]
@@ -15,7 +18,7 @@ pub enum TapType {
   Input,
 }
 
-#[derive(Debug, Serialize, Deserialize, TS)]
+#[derive(Clone, Debug, Serialize, Deserialize, TS)]
 #[ts(export)]
 pub struct Tap {
   pub tp: TapType,
@@ -23,7 +26,7 @@ pub struct Tap {
   pub weight: f32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
 #[ts(export)]
@@ -31,32 +34,54 @@ pub struct LowpassControlBlock {
   pub taps: Vec<Tap>,
 }
 
+impl Params for LowpassControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("taps", [Key::Index(ix), rest @ ..]) => self.taps.get_mut(*ix)?.param_mut(rest),
+      _ => None,
+    }
+  }
+}
+
+impl Params for Tap {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("weight", []) => Some(&mut self.weight),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct LowpassState {
   src: usize,
   dst: usize,
   ix: usize,
   ci: usize,
-  memory_rec: Vec<f32>,
-  memory_input: Vec<f32>,
+  channels: usize,
+  // One ring buffer per channel
+  memory_rec: Vec<Vec<f32>>,
+  memory_input: Vec<Vec<f32>>,
 }
 
 impl LowpassState {
-  pub fn new(src: usize, dst: usize, ci: usize) -> Self {
+  pub fn new(src: usize, dst: usize, ci: usize, channels: Option<usize>) -> Self {
+    let channels = ugen_channels(channels);
     LowpassState {
       src,
       dst,
       ci,
+      channels,
       ix: 0,
-      memory_rec: vec![0.; HISTORY_SIZE],
-      memory_input: vec![0.; HISTORY_SIZE],
+      memory_rec: vec![vec![0.; HISTORY_SIZE]; channels],
+      memory_input: vec![vec![0.; HISTORY_SIZE]; channels],
     }
   }
 
-  fn do_tap(&self, tap: &Tap) -> f32 {
+  fn do_tap(&self, ch: usize, tap: &Tap) -> f32 {
     let memory = match tap.tp {
-      TapType::Input => &self.memory_input,
-      TapType::Rec => &self.memory_rec,
+      TapType::Input => &self.memory_input[ch],
+      TapType::Rec => &self.memory_rec[ch],
     };
     let memval =
       memory[((self.ix as i32) - (tap.pos as i32)).rem_euclid(HISTORY_SIZE as i32) as usize];
@@ -64,21 +89,24 @@ impl LowpassState {
   }
 
   fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &LowpassControlBlock) -> bool {
-    for bus_ix in 0..gen.audio_bus[0].len() {
+    for bus_ix in 0..gen.audio_bus[0][0].len() {
       // bus_ix is the index into the snippet of audio we are
       // currently processing self.ix is the index into the ring
       // buffers that remember past input (memory_input), and past
       // output (memory_rec) of this ugen.
 
-      // Make sure the current input value is in memory_input at the
-      // current time (because do_tap might need to read it)
-      self.memory_input[self.ix] = gen.audio_bus[self.src][bus_ix];
-      let mut wet = 0.;
-      for tap in ctl.taps.iter() {
-        wet += self.do_tap(tap);
+      for ch in 0..self.channels {
+        // Make sure the current input value is in memory_input at the
+        // current time (because do_tap might need to read it)
+        self.memory_input[ch][self.ix] =
+          read_channel(&gen.audio_bus[self.src], self.channels, ch, bus_ix);
+        let mut wet = 0.;
+        for tap in ctl.taps.iter() {
+          wet += self.do_tap(ch, tap);
+        }
+        write_channel(&mut gen.audio_bus[self.dst], self.channels, ch, bus_ix, wet);
+        self.memory_rec[ch][self.ix] = wet;
       }
-      gen.audio_bus[self.dst][bus_ix] = wet;
-      self.memory_rec[self.ix] = wet;
       self.ix = (self.ix + 1) % HISTORY_SIZE;
     }
     true
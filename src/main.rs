@@ -2,41 +2,67 @@
 
 mod allpass;
 mod audio;
+mod automation;
+mod backend;
 mod consts;
+mod control;
 mod drum;
+mod engine;
 mod envelope;
+mod fade;
 mod freeverb;
 mod gain;
+mod graph;
+mod lfo;
 mod lowpass;
 mod meter;
 mod midi;
+mod midi_learn;
 mod midi_manager;
+mod modulation;
 mod notegen;
+mod pan;
+mod param;
+mod patch;
 mod reasonable_synth;
 mod reduce;
+mod render;
 mod reverb;
 mod sequencer;
+mod smooth;
 mod state;
 mod synth;
+mod tuning;
 mod ugen;
 mod ugen_group;
 mod util;
+mod validate;
+mod wav;
 mod wavetables;
 mod webserver;
 
-use audio::{BUF_SIZE, CHANNELS};
-use clap::Parser;
-use consts::BUS_OUT;
-use midi::{Message, MidiService};
+use anyhow::Context;
+use audio::AudioConfig;
+use backend::BackendKind;
+use clap::{Parser, Subcommand};
+use consts::{DEFAULT_SAMPLE_RATE_hz, BUS_OUT, DEFAULT_PERIOD_FRAMES};
+use control::{ControlGuard, ControlState};
+use engine::{Engine, EngineCommand, Telemetry, TelemetryConsumer};
+use midi::{Message, MidiService, PortSpec};
+use patch::Patch;
 use sequencer::sequencer_loop;
-use state::{State, StateGuard, DEFAULT_DRUM_CONTROL_BLOCK};
-use ugen::UgenState;
-use ugen_group::UgenGroupState;
+use state::{drum_control_block, ControlBlock};
+use tuning::Tuning;
+use ugen::UgenSpec;
 use util::{depoison, JoinHandle, UnitHandle};
-use webserver::{WebMessage, WebOrSubMessage};
+use validate::ValidationError;
+use wav::WavFormat;
+use webserver::{SynthMessage, WebMessage, WebOrSubMessage};
 
 use std::error::Error;
 use std::io::stdin;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 fn main() {
@@ -46,77 +72,293 @@ fn main() {
   }
 }
 
-fn reduce_web_message(m: WebMessage, s: &mut State) {
+// Changes that could make the audio thread index out of range are
+// checked first, and refused with an error if they're bad.
+fn reduce_web_message(m: WebMessage, s: &mut ControlState) -> Result<(), ValidationError> {
   match m {
     WebMessage::Drum => {
-      let ugen = s.new_drum(DEFAULT_DRUM_CONTROL_BLOCK);
-      add_ugen_to_group(&mut s.fixed_ugens, ugen);
+      if let Some(ugen) = s.new_drum(&drum_control_block(0)) {
+        s.send(EngineCommand::AddToGroup(Box::new(ugen)));
+      }
     },
     WebMessage::Quit => {
       s.going = false;
+      s.send(EngineCommand::Quit);
     },
     WebMessage::SetSequencer { inst, pat, on } => {
+      validate::check_sequencer_step(inst, pat)?;
       s.sequencer.set(inst, pat, on);
     },
     WebMessage::Reconfigure { specs } => {
-      s.fixed_ugens = specs //
-        .into_iter()
-        .map(UgenState::new)
-        .collect();
+      validate::check_specs(&specs, &s.control_blocks)?;
+      s.reconfigure(specs)?;
+    },
+    WebMessage::SetControlBlock { name, ctl } => {
+      validate::check_control_block(&name, &ctl, &s.specs)?;
+      s.set_control_block(name, ctl);
+    },
+    WebMessage::SetParam { block, path, value } => {
+      let ctl = match s.control_blocks.get(&block) {
+        Some(ctl) => param::set_param(&block, ctl, &path, value)?,
+        None => return Err(ValidationError::NoSuchControlBlock { name: block }),
+      };
+      // A number can be fine on its own, but not in its block, like a
+      // tuning's reference frequency
+      validate::check_control_block(&block, &ctl, &s.specs)?;
+      s.set_control_block(block, ctl);
+    },
+    WebMessage::SetParamRamp { ramp_s } => {
+      s.send(EngineCommand::SetParamRamp(ramp_s.max(0.0)));
+    },
+    WebMessage::DeleteControlBlock { name } => {
+      validate::check_delete(
+        &name,
+        &s.specs,
+        &s.control_blocks,
+        &s.lanes,
+        &s.mod_routes,
+        &s.midi_bindings,
+      )?;
+      s.delete_control_block(&name);
     },
-    WebMessage::SetControlBlock { index, ctl } => {
-      s.control_blocks[index] = Some(ctl);
+    WebMessage::SetLane { name, lane } => {
+      validate::check_lane(&name, &lane, &s.control_blocks)?;
+      s.set_lane(name, lane);
+    },
+    WebMessage::DeleteLane { name } => {
+      s.delete_lane(&name);
+    },
+    WebMessage::SetModRoutes { routes } => {
+      validate::check_routes(&routes, &s.control_blocks)?;
+      s.set_mod_routes(routes);
+    },
+    WebMessage::LoadTuning {
+      block,
+      scale,
+      keyboard,
+      reference_hz,
+    } => {
+      let mut ctl = match s.control_blocks.get(&block) {
+        Some(ControlBlock::Reasonable(ctl)) => ctl.clone(),
+        Some(other) => {
+          return Err(ValidationError::ControlBlockMismatch {
+            name: block,
+            expected: "Reasonable".to_string(),
+            found: other.kind().to_string(),
+          })
+        },
+        None => return Err(ValidationError::NoSuchControlBlock { name: block }),
+      };
+      let mut tuning = match load_tuning(scale.as_deref(), keyboard.as_deref()) {
+        Ok(tuning) => tuning,
+        Err(e) => {
+          return Err(ValidationError::BadTuning {
+            name: block,
+            reason: format!("couldn't be loaded: {:#}", e),
+          })
+        },
+      };
+      if let Some(reference_hz) = reference_hz {
+        tuning.keyboard.reference_hz = reference_hz;
+      }
+      ctl.tuning = Some(tuning);
+      let ctl = ControlBlock::Reasonable(ctl);
+      validate::check_control_block(&block, &ctl, &s.specs)?;
+      s.set_control_block(block, ctl);
+    },
+    WebMessage::MidiLearn { target } => {
+      validate::check_midi_target(&target, &s.control_blocks)?;
+      s.learn(target);
+    },
+    WebMessage::CancelMidiLearn => {
+      s.cancel_learn();
+    },
+    WebMessage::SetMidiBindings { bindings } => {
+      validate::check_midi_bindings(&bindings, &s.control_blocks)?;
+      s.set_midi_bindings(bindings);
+    },
+    // Only make sense coming from a web client, so that there's
+    // someone to reply to; see reduce_web_or_sub_message.
+    WebMessage::GetState | WebMessage::ListControlBlocks => (),
+    WebMessage::SavePatch { name } => {
+      let res = patch::path_of_name(&name).and_then(|path| patch::save(&path, &s.patch()));
+      if let Err(e) = res {
+        return Err(ValidationError::BadPatch {
+          name,
+          reason: format!("couldn't be saved: {:#}", e),
+        });
+      }
+    },
+    WebMessage::LoadPatch { name } => {
+      match patch::path_of_name(&name).and_then(|path| patch::load(&path)) {
+        Ok(patch) => {
+          apply_patch(patch, s)?;
+          // so that every client's controls reflect the new sound
+          let snapshot = s.snapshot();
+          s.send_to_clients(snapshot);
+        },
+        Err(e) => {
+          return Err(ValidationError::BadPatch {
+            name,
+            reason: format!("couldn't be loaded: {:#}", e),
+          })
+        },
+      }
     },
   }
+  Ok(())
+}
+
+// From Scala files in the tunings directory
+fn load_tuning(scale: Option<&str>, keyboard: Option<&str>) -> anyhow::Result<Tuning> {
+  let mut tuning = Tuning::default();
+  if let Some(name) = scale {
+    tuning.scale = tuning::parse_scl(&tuning::load(name, "scl")?)
+      .with_context(|| format!("parsing scale {name}"))?;
+  }
+  if let Some(name) = keyboard {
+    tuning.keyboard = tuning::parse_kbm(&tuning::load(name, "kbm")?)
+      .with_context(|| format!("parsing keyboard mapping {name}"))?;
+  }
+  Ok(tuning)
 }
 
-fn reduce_web_or_sub_message(m: WebOrSubMessage, s: &mut State) {
+// A patch is checked as a whole, against the control blocks we'll
+// have once it's loaded, so that it can change what kind of block
+// lives where; and it's loaded entirely or not at all.
+fn apply_patch(patch: Patch, s: &mut ControlState) -> Result<(), ValidationError> {
+  let Patch {
+    specs,
+    control_blocks,
+    lanes,
+    mod_routes,
+    midi_bindings,
+  } = patch;
+  let mut merged = s.control_blocks.clone();
+  for (name, ctl) in control_blocks.iter() {
+    validate::check_control_block(name, ctl, &specs)?;
+    merged.insert(name.clone(), ctl.clone());
+  }
+  validate::check_specs(&specs, &merged)?;
+  for (name, lane) in lanes.iter() {
+    validate::check_lane(name, lane, &merged)?;
+  }
+  validate::check_routes(&mod_routes, &merged)?;
+  validate::check_midi_bindings(&midi_bindings, &merged)?;
+
+  s.reconfigure(specs)?;
+  for (name, ctl) in control_blocks.into_iter() {
+    s.set_control_block(name, ctl);
+  }
+  for (name, lane) in lanes.into_iter() {
+    s.set_lane(name, lane);
+  }
+  s.set_mod_routes(mod_routes);
+  s.set_midi_bindings(midi_bindings);
+  Ok(())
+}
+
+// The starting point for the engine: the default graph, overridden
+// by the patch given on the command line, if any.
+fn init_control_state(s: &mut ControlState, patch: Option<&PathBuf>) -> anyhow::Result<()> {
+  let specs = default_specs();
+  reduce_web_message(WebMessage::Reconfigure { specs }, s)?;
+  if let Some(path) = patch {
+    apply_patch(patch::load(path)?, s)
+      .with_context(|| format!("loading patch {}", path.display()))?;
+  }
+  Ok(())
+}
+
+fn reduce_web_or_sub_message(m: WebOrSubMessage, s: &mut ControlState) {
   match m {
-    WebOrSubMessage::WebMessage(m) => {
-      reduce_web_message(m, s);
+    WebOrSubMessage::WebMessage(id, WebMessage::GetState) => {
+      let snapshot = s.snapshot();
+      s.send_to_client(id, snapshot);
     },
-    WebOrSubMessage::SubMessage(tx) => {
-      s.websocket = Some(tx.clone());
+    WebOrSubMessage::WebMessage(id, WebMessage::ListControlBlocks) => {
+      let names = s.control_blocks.keys().cloned().collect();
+      s.send_to_client(id, SynthMessage::ControlBlockNames { names });
+    },
+    WebOrSubMessage::WebMessage(id, m) => {
+      if let Err(error) = reduce_web_message(m, s) {
+        let description = error.to_string();
+        s.send_to_client(id, SynthMessage::Error { error, description });
+      }
+    },
+    WebOrSubMessage::SubMessage(id, tx) => {
+      s.clients.add(id, tx);
+    },
+    WebOrSubMessage::Unsubscribe(id) => {
+      s.clients.remove(id);
     },
   }
 }
 
-fn mk_web_thread(sg: StateGuard) -> (UnitHandle, UnitHandle) {
+fn mk_web_thread(cg: ControlGuard) -> (UnitHandle, UnitHandle) {
   webserver::start(move |msg| {
-    let mut s = depoison(sg.lock())?;
+    let mut s = depoison(cg.lock())?;
     reduce_web_or_sub_message(msg, &mut s);
     Ok(())
   })
 }
 
-fn mk_sequencer_thread(sg: StateGuard) -> JoinHandle {
+fn mk_sequencer_thread(cg: ControlGuard) -> JoinHandle {
   std::thread::spawn(move || -> anyhow::Result<()> {
-    sequencer_loop(sg)?;
+    sequencer_loop(cg)?;
     Ok(())
   })
 }
 
-fn mk_midi_service(sg: StateGuard) -> anyhow::Result<MidiService> {
-  midi::MidiService::new(0, move |msg: &Message| -> anyhow::Result<()> {
-    let mut s: MutexGuard<State> = depoison(sg.lock())?;
-    reduce::midi_reducer(msg, &mut s)?;
+fn mk_midi_service(cg: ControlGuard, ports: &[String]) -> anyhow::Result<MidiService> {
+  let specs = ports.iter().map(|port| PortSpec::parse(port)).collect();
+  midi::MidiService::new(specs, move |msg: &Message| -> anyhow::Result<()> {
+    let mut s: MutexGuard<ControlState> = depoison(cg.lock())?;
+    s.send_midi(msg.clone());
+    s.send_to_clients(SynthMessage::Midi { msg: msg.clone() });
     Ok(())
   })
 }
 
-fn add_ugen_to_group(ugens: &mut Vec<UgenState>, ugen: UgenState) {
-  let maybe_group = ugens.iter_mut().find_map(|ugen| match ugen {
-    UgenState::UgenGroup(group) => Some(group),
-    _ => None,
-  });
-  if let Some(group) = maybe_group {
-    group.add(ugen);
-  } else {
-    println!("couldn't find ugen group");
+// How often the telemetry thread checks for news from the audio thread
+const TELEMETRY_POLL_MS: u64 = 10;
+
+fn handle_telemetry(t: Telemetry, s: &mut ControlState) {
+  match t {
+    Telemetry::Synth(msg) => s.send_to_clients(msg),
+    Telemetry::Warning(w) => println!("Warning: {}", w),
+    Telemetry::Garbage(_) => (), // dropped here, rather than on the audio thread
   }
 }
 
-fn mk_stdin_thread(sg: StateGuard) -> JoinHandle {
+fn mk_telemetry_thread(cg: ControlGuard, mut telemetry: TelemetryConsumer) -> JoinHandle {
+  std::thread::spawn(move || -> anyhow::Result<()> {
+    loop {
+      {
+        let mut s: MutexGuard<ControlState> = depoison(cg.lock())?;
+        while let Ok(t) = telemetry.pop() {
+          handle_telemetry(t, &mut s);
+        }
+        s.flush_commands();
+        if !s.going {
+          break;
+        }
+      }
+      std::thread::sleep(std::time::Duration::from_millis(TELEMETRY_POLL_MS));
+    }
+    Ok(())
+  })
+}
+
+// The ugens we start with, before any client has sent a Reconfigure
+fn default_specs() -> Vec<UgenSpec> {
+  vec![
+    // send midi notes straight to out
+    UgenSpec::UgenGroup { dst: BUS_OUT },
+  ]
+}
+
+fn mk_stdin_thread(cg: ControlGuard) -> JoinHandle {
   std::thread::spawn(move || -> anyhow::Result<()> {
     loop {
       let mut input = String::new();
@@ -124,14 +366,13 @@ fn mk_stdin_thread(sg: StateGuard) -> JoinHandle {
 
       match input.as_str() {
         "\n" => {
-          let mut s: MutexGuard<State> = depoison(sg.lock())?;
-          s.going = false;
+          let mut s: MutexGuard<ControlState> = depoison(cg.lock())?;
+          reduce_web_message(WebMessage::Quit, &mut s)?;
           break;
         },
         "k\n" => {
-          let mut s: MutexGuard<State> = depoison(sg.lock())?;
-          let ugen = s.new_drum(DEFAULT_DRUM_CONTROL_BLOCK);
-          add_ugen_to_group(&mut s.fixed_ugens, ugen);
+          let mut s: MutexGuard<ControlState> = depoison(cg.lock())?;
+          reduce_web_message(WebMessage::Drum, &mut s)?;
         },
         _ => println!("Didn't recognize {input}."),
       }
@@ -143,19 +384,87 @@ fn mk_stdin_thread(sg: StateGuard) -> JoinHandle {
 #[derive(Parser, Debug, Clone)]
 #[command(version, about)]
 pub struct Args {
-  // Sound card
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  // Where to send audio
+  #[arg(short, long, env, value_enum, default_value_t = BackendKind::Alsa)]
+  backend: BackendKind,
+
+  // Sound card, for the alsa backend
   #[arg(short = 'c', long, env)]
-  sound_card: u8,
+  sound_card: Option<u8>,
+
+  // Output WAV file, for the file backend
+  #[arg(short, long, env)]
+  output: Option<PathBuf>,
 
-  // Profiling interval, measured in number of BUF_SIZE-long audio sample generation periods
+  // Sample format of the output file, for the file backend
+  #[arg(short, long, env, value_enum, default_value_t = WavFormat::S16)]
+  format: WavFormat,
+
+  // Sample rate to ask the backend for, in Hz
+  #[arg(
+    short = 'r',
+    long,
+    env,
+    global = true,
+    default_value_t = DEFAULT_SAMPLE_RATE_hz,
+    value_parser = clap::value_parser!(u32).range(8000..=192000)
+  )]
+  sample_rate: u32,
+
+  // Number of frames to generate at a time, which the alsa backend
+  // also uses to size the device buffer
+  #[arg(
+    short = 'p',
+    long,
+    env,
+    global = true,
+    default_value_t = DEFAULT_PERIOD_FRAMES,
+    value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+  )]
+  period_size: usize,
+
+  // Midi input ports to open, by index or part of the name, e.g.
+  // --midi keystation --midi 2. Defaults to the first port that isn't
+  // ALSA's loopback.
+  #[arg(long, env, value_delimiter = ',')]
+  midi: Vec<String>,
+
+  // List midi input ports, and exit
+  #[arg(long)]
+  list_midi_ports: bool,
+
+  // Patch file to load at startup
+  #[arg(long, env, global = true)]
+  patch: Option<PathBuf>,
+
+  // Profiling interval, measured in number of period-long audio sample generation periods
   #[arg(long, env)]
-  profile_interval: Option<usize>,
+  profile_interval: Option<NonZeroUsize>,
+}
+
+impl Args {
+  fn audio_config(&self) -> AudioConfig {
+    AudioConfig {
+      sample_rate_hz: self.sample_rate,
+      period_frames: self.period_size,
+    }
+  }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+  #[command(about = "Render offline, faster than realtime, to a WAV file")]
+  Render(render::RenderArgs),
 }
 
-fn setup_ctrlc_handler(sg: StateGuard) {
+fn setup_ctrlc_handler(cg: ControlGuard) {
   ctrlc::set_handler(move || {
-    let mut s: MutexGuard<State> = sg.lock().unwrap();
-    reduce_web_message(WebMessage::Quit, &mut s);
+    let mut s: MutexGuard<ControlState> = cg.lock().unwrap();
+    // Quitting can't fail validation
+    let _ = reduce_web_message(WebMessage::Quit, &mut s);
   })
   .expect("Error setting Ctrl-C handler");
 }
@@ -163,23 +472,41 @@ fn setup_ctrlc_handler(sg: StateGuard) {
 fn run() -> Result<(), Box<dyn Error>> {
   let args = Args::parse();
 
-  let mono_buf_size = BUF_SIZE / (CHANNELS as usize);
-  let mut state = State::new(mono_buf_size);
+  if args.list_midi_ports {
+    midi::list_ports()?;
+    return Ok(());
+  }
 
-  state.fixed_ugens = vec![
-    // send midi notes straight to out
-    ugen::UgenState::UgenGroup(UgenGroupState::new(BUS_OUT)),
-  ];
+  if let Some(Command::Render(render_args)) = &args.command {
+    render::render(args.audio_config(), args.patch.as_ref(), render_args)?;
+    return Ok(());
+  }
+
+  let mut ads = audio::AudioService::new(&args)?;
+  println!("Audio config: {:?}", ads.config);
 
-  let state = Arc::new(Mutex::new(state));
+  let (mut engine, commands, telemetry) = Engine::new(ads.config);
 
-  let ms = mk_midi_service(state.clone())?;
-  mk_sequencer_thread(state.clone());
-  mk_stdin_thread(state.clone());
-  mk_web_thread(state.clone());
-  setup_ctrlc_handler(state.clone());
+  let mut control = ControlState::new(ads.config, engine.state.wavetables.clone(), commands);
+  init_control_state(&mut control, args.patch.as_ref())?;
+  let control = Arc::new(Mutex::new(control));
+
+  // Not having any midi input isn't fatal; we can still be driven
+  // from the web ui and the sequencer.
+  let ms = match mk_midi_service(control.clone(), &args.midi) {
+    Ok(ms) => Some(ms),
+    Err(e) => {
+      println!("Warning: no midi input: {}", e);
+      None
+    },
+  };
+  mk_sequencer_thread(control.clone());
+  mk_stdin_thread(control.clone());
+  mk_web_thread(control.clone());
+  mk_telemetry_thread(control.clone(), telemetry);
+  setup_ctrlc_handler(control.clone());
 
-  let ads = audio::AudioService::new(&args, &state, synth::Synth::new())?;
-  ads.render_thread.join().unwrap()?;
+  let render_thread = ads.start(&args, engine)?;
+  render_thread.join().unwrap()?;
   Ok(())
 }
@@ -1,8 +1,10 @@
+use crate::engine::Telemetry;
 use crate::state::{ControlBlocks, GenState};
 use crate::ugen::Ugen;
 use crate::webserver::SynthMessage;
 
-const METER_AMOUNT: usize = 44100 / 3;
+// How often we report to the client, in seconds
+const METER_INTERVAL_s: f32 = 1.0 / 3.0;
 
 #[derive(Clone, Debug)]
 pub struct MeterState {
@@ -14,11 +16,11 @@ pub struct MeterState {
 }
 
 impl MeterState {
-  pub fn new(src: usize) -> Self {
+  pub fn new(src: usize, sample_rate_hz: u32) -> Self {
     MeterState {
       src,
       ix: 0,
-      memory: vec![0.; METER_AMOUNT],
+      memory: vec![0.; ((sample_rate_hz as f32) * METER_INTERVAL_s) as usize],
       peak: 0.0,
       max_rms: 0.0,
     }
@@ -28,7 +30,8 @@ impl MeterState {
 impl Ugen for MeterState {
   fn run(&mut self, gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool {
     let len = self.memory.len();
-    for bus_ix in 0..gen.audio_bus[0].len() {
+    let src = &gen.audio_bus[self.src];
+    for bus_ix in 0..src[0].len() {
       // advance
 
       let do_tap = |offset: i32, scale: f32| -> f32 {
@@ -36,28 +39,30 @@ impl Ugen for MeterState {
       };
 
       let a = 0.99;
-      let sig = gen.audio_bus[self.src][bus_ix];
-      let mut mean_square = (1.0 - a) * sig * sig;
+      // Power is averaged over channels, peak is the loudest channel
+      let mut sig_sq = 0.;
+      for line in src.iter() {
+        let sig = line[bus_ix];
+        sig_sq += sig * sig;
+        self.peak = self.peak.max(sig.abs());
+      }
+      sig_sq /= src.len() as f32;
+      let mut mean_square = (1.0 - a) * sig_sq;
 
       mean_square += do_tap(1, a);
       self.max_rms = self.max_rms.max(mean_square.sqrt().abs());
-      self.peak = self.peak.max(sig.abs());
 
       self.ix = (self.ix + 1) % len;
       self.memory[self.ix] = mean_square;
 
       if self.ix == 0 {
-        if let Some(ws) = &gen.websocket {
-          let rms = mean_square.sqrt();
-          let msg = SynthMessage::Meter {
-            level: self.max_rms,
-            peak: self.peak,
-          };
-          let send_result = ws.try_send(msg);
-          if let Err(e) = send_result {
-            println!("meter error {:?}", e);
-          }
-        }
+        let msg = SynthMessage::Meter {
+          level: self.max_rms,
+          peak: self.peak,
+        };
+        // If the telemetry queue is full, skipping a meter reading
+        // does no harm.
+        let _ = gen.telemetry.push(Telemetry::Synth(msg));
         self.max_rms = 0.0;
         self.peak = 0.0;
       }
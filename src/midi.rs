@@ -1,18 +1,67 @@
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use anyhow::{anyhow, bail};
-use midir::{Ignore, MidiInput};
-use serde::Serialize;
+use anyhow::anyhow;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+// How often we look for midi devices coming and going
+const RESCAN_MS: u64 = 1000;
+
+// Without any --midi options we open the first port that isn't ALSA's
+// loopback port, which is always there
+const LOOPBACK_PORT: &str = "Midi Through";
+
+// Keeps the midi inputs open, reconnecting any that go away when they
+// come back. Stops when dropped.
 pub struct MidiService {
-  conn_in: midir::MidiInputConnection<()>,
+  stop: Arc<AtomicBool>,
+}
+
+type Callback = Arc<dyn Fn(&Message) -> anyhow::Result<()> + Send + Sync>;
+
+// Which port a --midi option means
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortSpec {
+  Any,
+  Index(usize),
+  // Part of the name, ignoring case
+  Name(String),
+}
+
+impl PortSpec {
+  pub fn parse(spec: &str) -> PortSpec {
+    match spec.parse::<usize>() {
+      Ok(ix) => PortSpec::Index(ix),
+      Err(_) => PortSpec::Name(spec.to_lowercase()),
+    }
+  }
+
+  fn find(&self, names: &[String]) -> Option<usize> {
+    match self {
+      PortSpec::Any => names.iter().position(|name| !name.contains(LOOPBACK_PORT)),
+      PortSpec::Index(ix) => (*ix < names.len()).then_some(*ix),
+      PortSpec::Name(part) => names
+        .iter()
+        .position(|name| name.to_lowercase().contains(part)),
+    }
+  }
+}
+
+struct Input {
+  spec: PortSpec,
+  // The name of the port we're connected to, so we notice it going
+  conn: Option<(String, MidiInputConnection<Parser>)>,
 }
 
 type Pitch = u8;
 
-#[derive(Debug, Serialize, Clone)]
+pub const MOD_WHEEL: u8 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "t")]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
@@ -27,86 +76,275 @@ pub enum Message {
     pitch: Pitch,
     channel: u8,
   },
-  PedalOn,
-  PedalOff,
+  // The sustain pedal. Older scripts don't give a channel.
+  PedalOn {
+    #[serde(default)]
+    channel: u8,
+  },
+  PedalOff {
+    #[serde(default)]
+    channel: u8,
+  },
+  // Any controller but the sustain pedal
+  ControlChange {
+    channel: u8,
+    controller: u8,
+    value: u8,
+  },
+  PolyAftertouch {
+    channel: u8,
+    pitch: Pitch,
+    pressure: u8,
+  },
+  ProgramChange {
+    channel: u8,
+    program: u8,
+  },
+  ChannelAftertouch {
+    channel: u8,
+    pressure: u8,
+  },
+  // From -8192 to 8191, 0 being the middle
+  PitchBend {
+    channel: u8,
+    value: i16,
+  },
 }
 
 use self::Message::*;
 
-fn message_of_vec(vec: &[u8]) -> Option<Message> {
-  match vec.len() {
-    3 => match vec[0] {
-      0x80..=0x8f => Some(NoteOff {
-        channel: vec[0] - 0x80,
-        pitch: vec[1],
-      }),
-      0x90..=0x9f => {
-        if vec[2] != 0 {
-          Some(NoteOn {
-            channel: vec[0] - 0x90,
-            pitch: vec[1],
-            velocity: vec[2],
-          })
-        } else {
-          Some(NoteOff {
-            channel: 0,
-            pitch: vec[1],
-          })
+// How many channels midi has
+pub const NUM_CHANNELS: u8 = 16;
+
+impl Message {
+  pub fn channel(&self) -> u8 {
+    match *self {
+      NoteOn { channel, .. }
+      | NoteOff { channel, .. }
+      | PedalOn { channel }
+      | PedalOff { channel }
+      | ControlChange { channel, .. }
+      | PolyAftertouch { channel, .. }
+      | ProgramChange { channel, .. }
+      | ChannelAftertouch { channel, .. }
+      | PitchBend { channel, .. } => channel,
+    }
+  }
+}
+
+// How many data bytes follow a channel voice status byte
+fn data_len(status: u8) -> usize {
+  match status & 0xf0 {
+    0xc0 | 0xd0 => 1,
+    _ => 2,
+  }
+}
+
+// A complete channel voice message: a status byte and its data
+fn message_of_status(status: u8, data: &[u8]) -> Option<Message> {
+  let channel = status & 0x0f;
+  match (status & 0xf0, data) {
+    (0x80, &[pitch, _]) => Some(NoteOff { channel, pitch }),
+    (0x90, &[pitch, 0]) => Some(NoteOff { channel, pitch }),
+    (0x90, &[pitch, velocity]) => Some(NoteOn {
+      channel,
+      pitch,
+      velocity,
+    }),
+    (0xa0, &[pitch, pressure]) => Some(PolyAftertouch {
+      channel,
+      pitch,
+      pressure,
+    }),
+    (0xb0, &[0x40, 0x00]) => Some(PedalOff { channel }),
+    (0xb0, &[0x40, _]) => Some(PedalOn { channel }),
+    (0xb0, &[controller, value]) => Some(ControlChange {
+      channel,
+      controller,
+      value,
+    }),
+    (0xc0, &[program]) => Some(ProgramChange { channel, program }),
+    (0xd0, &[pressure]) => Some(ChannelAftertouch { channel, pressure }),
+    (0xe0, &[lsb, msb]) => Some(PitchBend {
+      channel,
+      value: (((msb as i16) << 7) | lsb as i16) - 8192,
+    }),
+    _ => None,
+  }
+}
+
+// Turns the bytes a midi port sends into Messages. Keeps track of
+// running status, so that a status byte can be left out when it's the
+// same as the last one, and ignores realtime bytes (clock, start, stop
+// and so on) wherever they turn up, even in the middle of a message.
+// System messages, including sysex, are skipped.
+#[derive(Debug, Default)]
+pub struct Parser {
+  status: Option<u8>,
+  data: Vec<u8>,
+  in_sysex: bool,
+}
+
+impl Parser {
+  pub fn new() -> Parser {
+    Parser::default()
+  }
+
+  pub fn parse(&mut self, bytes: &[u8]) -> Vec<Message> {
+    let mut messages = vec![];
+    for &byte in bytes {
+      match byte {
+        0xf8..=0xff => (),
+        0xf0 => {
+          self.status = None;
+          self.in_sysex = true;
+        },
+        0xf1..=0xf7 => {
+          self.status = None;
+          self.in_sysex = false;
+        },
+        0x80..=0xef => {
+          self.status = Some(byte);
+          self.data.clear();
+          self.in_sysex = false;
+        },
+        _ if self.in_sysex => (),
+        _ => {
+          let Some(status) = self.status else {
+            continue;
+          };
+          self.data.push(byte);
+          if self.data.len() == data_len(status) {
+            messages.extend(message_of_status(status, &self.data));
+            self.data.clear();
+          }
+        },
+      }
+    }
+    messages
+  }
+}
+
+pub fn port_names() -> anyhow::Result<Vec<String>> {
+  let midi_in = MidiInput::new("rsynth ports")?;
+  let names = midi_in
+    .ports()
+    .iter()
+    .map(|port| midi_in.port_name(port).unwrap_or_else(|_| "?".to_string()))
+    .collect();
+  Ok(names)
+}
+
+pub fn list_ports() -> anyhow::Result<()> {
+  for (ix, name) in port_names()?.iter().enumerate() {
+    println!("{ix}: {name}");
+  }
+  Ok(())
+}
+
+fn connect(name: &str, k: Callback) -> anyhow::Result<MidiInputConnection<Parser>> {
+  let mut midi_in = MidiInput::new("rsynth input")?;
+  midi_in.ignore(Ignore::None);
+  let ports = midi_in.ports();
+  let port = ports
+    .iter()
+    .find(|port| midi_in.port_name(port).is_ok_and(|n| n == name))
+    .ok_or(anyhow!("midi port {name:?} went away"))?
+    .clone();
+  midi_in
+    .connect(
+      &port,
+      "rsynth",
+      move |stamp, message, parser| {
+        println!("{}: {:?} (len = {})", stamp, message, message.len());
+        for msg in parser.parse(message) {
+          if let Err(e) = k(&msg) {
+            println!("Error in midi callback: {}", e);
+          }
         }
       },
-      0xb0 => match vec[1] {
-        0x40 => match vec[2] {
-          0x00 => Some(PedalOff),
-          _ => Some(PedalOn),
-        },
-        _ => None,
+      Parser::new(),
+    )
+    .map_err(|e| anyhow!("can't connect to midi port {name:?}: {e}"))
+}
+
+// Drops inputs whose ports have gone, and connects any that aren't
+// connected to a matching port, if there is one.
+fn rescan(inputs: &mut [Input], k: &Callback) -> anyhow::Result<()> {
+  let names = port_names()?;
+  for input in inputs.iter_mut() {
+    if let Some((name, _)) = &input.conn {
+      if !names.contains(name) {
+        println!("Midi input {name:?} went away");
+        input.conn = None;
+      }
+    }
+  }
+  for ix in 0..inputs.len() {
+    if inputs[ix].conn.is_some() {
+      continue;
+    }
+    let Some(port) = inputs[ix].spec.find(&names) else {
+      continue;
+    };
+    let name = &names[port];
+    // Two options naming the same port don't open it twice
+    let open = inputs
+      .iter()
+      .any(|i| matches!(&i.conn, Some((n, _)) if n == name));
+    if open {
+      continue;
+    }
+    match connect(name, k.clone()) {
+      Ok(conn) => {
+        println!("Midi input: {name}");
+        inputs[ix].conn = Some((name.clone(), conn));
       },
-      _ => None,
-    },
-    _ => None,
+      Err(e) => println!("Warning: {e}"),
+    }
   }
+  Ok(())
 }
 
 impl MidiService {
-  pub fn new<C>(source_index: usize, k: C) -> anyhow::Result<MidiService>
+  // Inputs that aren't there yet are waited for, rather than being an
+  // error, so that the synth can start before the keyboard's plugged in.
+  pub fn new<C>(specs: Vec<PortSpec>, k: C) -> anyhow::Result<MidiService>
   where
     C: Fn(&Message) -> anyhow::Result<()> + std::marker::Send + Sync + 'static,
   {
-    let mut midi_in = MidiInput::new("midir input")?;
-    midi_in.ignore(Ignore::None);
-
-    let midi_device_num = 1;
-    let in_port = midi_in
-      .ports()
-      .get(midi_device_num)
-      .ok_or(anyhow!("Invalid port number"))?
-      .clone();
-
-    println!("\nOpening connections");
-    let in_port_name = midi_in.port_name(&in_port)?;
-
-    let conn_in_result = midi_in.connect(
-      &in_port,
-      "midir-print",
-      move |stamp, message, _| {
-        println!("{}: {:?} (len = {})", stamp, message, message.len());
-        match message_of_vec(message) {
-          Some(msg) => match k(&msg) {
-            Ok(()) => (),
-            Err(e) => println!("Error in midi callback: {}", e.to_string()),
-          },
-          None => println!("Warning, unknown midi message: {:?}", message),
-        }
-      },
-      (),
-    );
-
-    let conn_in: midir::MidiInputConnection<()> = match conn_in_result {
-      Ok(v) => v,
-      Err(e) => bail!("Error: Can't make midi input connection: {}", e.to_string()),
+    let k: Callback = Arc::new(k);
+    let specs = if specs.is_empty() {
+      vec![PortSpec::Any]
+    } else {
+      specs
     };
+    let mut inputs: Vec<Input> = specs
+      .into_iter()
+      .map(|spec| Input { spec, conn: None })
+      .collect();
+    rescan(&mut inputs, &k)?;
+    for input in inputs.iter().filter(|input| input.conn.is_none()) {
+      println!("Waiting for midi input {:?}", input.spec);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    std::thread::spawn(move || {
+      while !stopped.load(Ordering::Relaxed) {
+        std::thread::sleep(std::time::Duration::from_millis(RESCAN_MS));
+        if let Err(e) = rescan(&mut inputs, &k) {
+          println!("Warning: couldn't look for midi inputs: {e}");
+        }
+      }
+    });
+    Ok(MidiService { stop })
+  }
+}
 
-    Ok(MidiService { conn_in })
+impl Drop for MidiService {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
   }
 }
 
@@ -141,3 +379,66 @@ impl Error for MidiError {
     None
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{Message, Parser, PortSpec};
+
+  #[test]
+  fn finds_ports_by_index_or_name() {
+    let names = vec![
+      "Midi Through:Midi Through Port-0 14:0".to_string(),
+      "Keystation 49:Keystation 49 MIDI 1 20:0".to_string(),
+      "nanoKONTROL2:nanoKONTROL2 MIDI 1 24:0".to_string(),
+    ];
+    assert_eq!(PortSpec::Any.find(&names), Some(1));
+    assert_eq!(PortSpec::parse("2").find(&names), Some(2));
+    assert_eq!(PortSpec::parse("3").find(&names), None);
+    assert_eq!(PortSpec::parse("NanoKontrol").find(&names), Some(2));
+    assert_eq!(PortSpec::parse("launchpad").find(&names), None);
+    assert_eq!(PortSpec::Any.find(&names[..1]), None);
+  }
+
+  #[test]
+  fn parses_channel_voice_messages() {
+    let mut parser = Parser::new();
+    assert_eq!(
+      parser.parse(&[0xb3, 0x01, 0x7f, 0xe0, 0x00, 0x40, 0xc2, 0x05]),
+      vec![
+        Message::ControlChange {
+          channel: 3,
+          controller: 1,
+          value: 127
+        },
+        Message::PitchBend {
+          channel: 0,
+          value: 0
+        },
+        Message::ProgramChange {
+          channel: 2,
+          program: 5
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn follows_running_status_through_realtime_bytes() {
+    let mut parser = Parser::new();
+    let note = |pitch| Message::NoteOn {
+      channel: 1,
+      pitch,
+      velocity: 100,
+    };
+    assert_eq!(parser.parse(&[0x91, 60, 100]), vec![note(60)]);
+    // a clock tick in the middle of a message, and no status byte
+    assert_eq!(parser.parse(&[62, 0xf8, 100, 64]), vec![note(62)]);
+    assert_eq!(
+      parser.parse(&[0, 0xf0, 0x7e, 0x01, 0xf7, 60, 0]),
+      vec![Message::NoteOff {
+        channel: 1,
+        pitch: 64
+      }]
+    );
+  }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::automation::Curve;
+
+// A number in a control block for a midi controller to set, and the
+// range the controller sweeps it over
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct MidiTarget {
+  pub block: String,
+  // As for SetParam; see param.rs
+  pub path: String,
+  pub min: f32,
+  pub max: f32,
+  #[serde(default)]
+  pub curve: Curve,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct MidiBinding {
+  // On any channel
+  pub controller: u8,
+  pub target: MidiTarget,
+}
+
+impl MidiTarget {
+  // Where controller value `value` puts the number
+  pub fn scaled(&self, value: u8) -> f32 {
+    let x = (value as f32) / 127.0;
+    match self.curve {
+      Curve::Linear => self.min + (self.max - self.min) * x,
+      Curve::Exp => self.min * (self.max / self.min).powf(x),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MidiTarget;
+  use crate::automation::Curve;
+
+  fn target(curve: Curve) -> MidiTarget {
+    MidiTarget {
+      block: "fx".to_string(),
+      path: "scale".to_string(),
+      min: 0.1,
+      max: 10.0,
+      curve,
+    }
+  }
+
+  #[test]
+  fn sweeps_from_min_to_max() {
+    let linear = target(Curve::Linear);
+    assert_eq!(linear.scaled(0), 0.1);
+    assert_eq!(linear.scaled(127), 10.0);
+
+    let exp = target(Curve::Exp);
+    assert!((exp.scaled(0) - 0.1).abs() < 1e-6);
+    assert!((exp.scaled(127) - 10.0).abs() < 1e-4);
+    let middle = exp.scaled(64);
+    assert!(middle > 0.9 && middle < 1.1);
+  }
+}
@@ -1,33 +1,201 @@
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
 use crate::consts::NUM_KEYS;
-use crate::notegen::NotegenState;
+use crate::midi::NUM_CHANNELS;
+use crate::notegen::{NoteMode, NotegenState};
+use crate::param::{self, Key, Params};
 use crate::state::{ControlBlocks, GenState, KeyState};
-use crate::ugen::Ugen;
+use crate::ugen::{Advice, Ugen};
+
+// Which voice makes room for a new note, once a midi manager is
+// playing as many as it's allowed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum Steal {
+  #[default]
+  Oldest,
+  Quietest,
+  // One playing the same note, if there is one, and otherwise the
+  // oldest
+  SameNote,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Polyphony {
+  // At least one, whatever this says
+  pub voices: usize,
+  pub steal: Steal,
+}
+
+impl Default for Polyphony {
+  fn default() -> Self {
+    Polyphony {
+      voices: 16,
+      steal: Steal::Oldest,
+    }
+  }
+}
+
+// Which of the notes held down a mono manager plays
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum Priority {
+  // The one pressed most recently
+  #[default]
+  Last,
+  Low,
+  High,
+}
+
+// Playing one note at a time, like a classic monosynth
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Mono {
+  pub priority: Priority,
+  // If set, a note played while another is held changes the pitch
+  // without starting the envelope again
+  pub legato: bool,
+  // Roughly how long the pitch takes to get to a new note, gliding
+  // from the last one; 0 jumps straight there
+  pub glide_s: f32,
+}
+
+impl Params for Mono {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("glide_s", []) => Some(&mut self.glide_s),
+      _ => None,
+    }
+  }
+}
+
+impl Mono {
+  pub fn pitch(&self, held: &[u8]) -> Option<u8> {
+    match self.priority {
+      Priority::Last => held.last().copied(),
+      Priority::Low => held.iter().min().copied(),
+      Priority::High => held.iter().max().copied(),
+    }
+  }
+}
 
 #[derive(Debug)]
 pub struct MidiManagerState {
   pub dst: usize,
   // Is the sustain pedal on?
   pub pedal: bool,
-  // This is NUM_KEYS long, one keystate for every physical key.
+  // One keystate for every midi note on every channel, NUM_KEYS per
+  // channel
   pub key_state: Vec<KeyState>,
   pub notegen_state: Vec<Option<NotegenState>>,
   pub ci: usize, // control block for new notes
+  // The midi channels it plays, one bit each
+  channels: u16,
+  // Notes started so far, for telling which voice is oldest
+  pub notes_started: u64,
+  // In mono mode: the notes held down, in the order they were pressed,
+  // and the voice playing them, by index and serial number
+  pub held: Vec<u8>,
+  pub mono_voice: Option<(usize, u64)>,
+  pub last_velocity: u8,
+  // Passed on to every note; see Advice
+  pub bend: f32,
+  pub mod_wheel: f32,
 }
 
 impl MidiManagerState {
-  pub fn new(dst: usize, ci: usize) -> MidiManagerState {
+  pub fn new(dst: usize, ci: usize, channels: Option<&[u8]>) -> MidiManagerState {
+    let channels = match channels {
+      Some(channels) => channels.iter().fold(0, |bits, &ch| bits | channel_bit(ch)),
+      None => u16::MAX,
+    };
     MidiManagerState {
       dst,
       pedal: false,
-      key_state: vec![KeyState::Off; NUM_KEYS],
+      key_state: vec![KeyState::Off; NUM_KEYS * NUM_CHANNELS as usize],
       notegen_state: vec![],
       ci,
+      channels,
+      notes_started: 0,
+      // Room for every midi note, so that pressing them doesn't
+      // allocate
+      held: Vec::with_capacity(NUM_KEYS),
+      mono_voice: None,
+      last_velocity: 0,
+      bend: 0.0,
+      mod_wheel: 0.0,
     }
   }
+
+  pub fn plays(&self, channel: u8) -> bool {
+    self.channels & channel_bit(channel) != 0
+  }
+
+  // The voice playing in mono mode, unless it's finished or been
+  // stolen
+  pub fn mono_voice(&mut self) -> Option<&mut NotegenState> {
+    let (ix, serial) = self.mono_voice?;
+    self.notegen_state[ix]
+      .as_mut()
+      .filter(|n| n.serial == serial && !n.stolen())
+  }
+
+  // Steals voices, if need be, so that there's room for one more, for
+  // a note at `pitch`. Stolen voices fade out over a few milliseconds
+  // rather than clicking off, and don't count while they do.
+  pub fn make_room(&mut self, polyphony: &Polyphony, pitch: u8) {
+    loop {
+      let playing = || {
+        self
+          .notegen_state
+          .iter()
+          .enumerate()
+          .filter_map(|(ix, n)| Some((ix, n.as_ref().filter(|n| !n.stolen())?)))
+      };
+      if playing().count() < polyphony.voices.max(1) {
+        return;
+      }
+      let oldest = playing().min_by_key(|(_, n)| n.serial);
+      let victim = match polyphony.steal {
+        Steal::Oldest => oldest,
+        Steal::Quietest => playing().min_by(|(_, a), (_, b)| a.level().total_cmp(&b.level())),
+        Steal::SameNote => playing().find(|(_, n)| n.pitch == pitch).or(oldest),
+      };
+      let Some((victim, _)) = victim else {
+        return;
+      };
+      if let Some(notegen) = &mut self.notegen_state[victim] {
+        notegen.steal();
+      }
+      for ks in self.key_state.iter_mut() {
+        if let KeyState::On { ugen_ix } | KeyState::Held { ugen_ix } = *ks {
+          if ugen_ix == victim {
+            *ks = KeyState::Off;
+          }
+        }
+      }
+    }
+  }
+}
+
+// Scripts can send messages on channels midi doesn't have, which no
+// one plays
+fn channel_bit(channel: u8) -> u16 {
+  1u16.checked_shl(channel as u32).unwrap_or(0)
 }
 
 impl Ugen for MidiManagerState {
   fn run(&mut self, mut gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool {
+    let advice = Advice {
+      note_mode: NoteMode::Run,
+      bend: self.bend,
+      mod_wheel: self.mod_wheel,
+    };
+    let mut gen = gen.readvise(&advice);
     for mut onotegen in self.notegen_state.iter_mut() {
       if let Some(notegen) = onotegen {
         if !notegen.run(gen.reborrow(), tick_s, ctl) {
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::param::{self, Key, Params};
+use crate::state::{ControlBlocks, ControlSlots, State};
+use crate::ugen::UgenState;
+
+// Where modulation comes from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[serde(tag = "t")]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum ModSource {
+  // The LFO ugen whose control block is `name`, from -1 to 1
+  Lfo { name: String },
+  // A midi controller, from 0 to 1
+  Cc { controller: u8 },
+  // Only per voice: the note's velocity, from 0 to 1
+  Velocity,
+  // Only per voice: the note's pitch, in octaves above middle C
+  Key,
+}
+
+// What a voice can have modulated, by its own sources as well as
+// global ones
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum VoiceParam {
+  // In semitones
+  Pitch,
+  // Added to 1, and multiplied into the voice's envelope
+  Amp,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[serde(tag = "t")]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub enum ModDestination {
+  // A number in a control block, as for SetParam; see param.rs
+  Param { block: String, path: String },
+  // Every voice played by a midi manager using control block `block`
+  Voice { block: String, param: VoiceParam },
+}
+
+// One row of the modulation matrix. Rows with the same destination
+// add up, and their sum is added to the destination's own value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ModRoute {
+  pub source: ModSource,
+  pub destination: ModDestination,
+  pub depth: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SourceRef {
+  Lfo(usize),
+  Cc(u8),
+  Velocity,
+  Key,
+}
+
+// A number in a control block that's being modulated. The value we
+// modulate around is whatever was there before we started, or
+// whatever anyone else (a client, or automation) last put there.
+#[derive(Debug)]
+struct Target {
+  index: usize,
+  keys: Vec<Key>,
+  base: f32,
+  // What we last wrote, so that we can tell if anyone else has
+  written: f32,
+  offset: f32,
+}
+
+#[derive(Debug)]
+struct ParamRoute {
+  source: SourceRef,
+  target: usize,
+  depth: f32,
+}
+
+#[derive(Debug)]
+pub struct VoiceRoute {
+  source: SourceRef,
+  // The control block of the voices it applies to
+  pub index: usize,
+  pub param: VoiceParam,
+  depth: f32,
+  // Of a global source, as of this period
+  value: f32,
+}
+
+impl VoiceRoute {
+  // How far this route moves a voice with this key and velocity
+  pub fn amount(&self, key: f32, velocity: f32) -> f32 {
+    let value = match self.source {
+      SourceRef::Velocity => velocity,
+      SourceRef::Key => key,
+      SourceRef::Lfo(_) | SourceRef::Cc(_) => self.value,
+    };
+    self.depth * value
+  }
+}
+
+// The modulation matrix as the audio thread runs it, with names
+// turned into slots and paths parsed.
+#[derive(Debug, Default)]
+pub struct Modulation {
+  targets: Vec<Target>,
+  param_routes: Vec<ParamRoute>,
+  pub voice_routes: Vec<VoiceRoute>,
+}
+
+impl Modulation {
+  // `routes` should already have been validated, and `slots` has to
+  // have a slot for every control block they mention
+  pub fn new(routes: &[ModRoute], slots: &ControlSlots) -> Modulation {
+    let mut modulation = Modulation::default();
+    for route in routes {
+      let source = match &route.source {
+        ModSource::Lfo { name } => SourceRef::Lfo(slots[name]),
+        ModSource::Cc { controller } => SourceRef::Cc(*controller),
+        ModSource::Velocity => SourceRef::Velocity,
+        ModSource::Key => SourceRef::Key,
+      };
+      match &route.destination {
+        ModDestination::Param { block, path } => {
+          let index = slots[block];
+          let keys = param::parse_path(path);
+          let targets = &mut modulation.targets;
+          let target = match targets
+            .iter()
+            .position(|t| t.index == index && t.keys == keys)
+          {
+            Some(target) => target,
+            None => {
+              targets.push(Target {
+                index,
+                keys,
+                base: 0.0,
+                written: f32::NAN,
+                offset: 0.0,
+              });
+              targets.len() - 1
+            },
+          };
+          modulation.param_routes.push(ParamRoute {
+            source,
+            target,
+            depth: route.depth,
+          });
+        },
+        ModDestination::Voice { block, param } => modulation.voice_routes.push(VoiceRoute {
+          source,
+          index: slots[block],
+          param: *param,
+          depth: route.depth,
+          value: 0.0,
+        }),
+      }
+    }
+    modulation
+  }
+
+  // Puts back the values we've been modulating around, before the
+  // matrix is replaced
+  pub fn restore(&self, control_blocks: &mut ControlBlocks) {
+    for target in self.targets.iter() {
+      if let Some(param) = target_param(control_blocks, target) {
+        if *param == target.written {
+          *param = target.base;
+        }
+      }
+    }
+  }
+}
+
+fn target_param<'a>(control_blocks: &'a mut ControlBlocks, target: &Target) -> Option<&'a mut f32> {
+  control_blocks
+    .get_mut(target.index)?
+    .as_mut()?
+    .param_mut(&target.keys)
+}
+
+// Reads the global sources once a period, for voices to use, and
+// moves every modulated control block number. LFOs are read as they
+// were left at the end of the last period.
+pub fn run(s: &mut State) {
+  let State {
+    modulation,
+    fixed_ugens,
+    control_blocks,
+    midi_cc,
+    ..
+  } = s;
+  let source_value = |source: SourceRef| match source {
+    SourceRef::Lfo(ci) => fixed_ugens
+      .iter()
+      .find_map(|node| match &*node.ugen {
+        UgenState::Lfo(lfo) if lfo.ci == ci => Some(lfo.value()),
+        _ => None,
+      })
+      .unwrap_or(0.0),
+    SourceRef::Cc(controller) => midi_cc.get(controller as usize).copied().unwrap_or(0.0),
+    // Per voice; see VoiceRoute::amount
+    SourceRef::Velocity | SourceRef::Key => 0.0,
+  };
+
+  for route in modulation.voice_routes.iter_mut() {
+    route.value = source_value(route.source);
+  }
+  for target in modulation.targets.iter_mut() {
+    target.offset = 0.0;
+  }
+  for route in modulation.param_routes.iter() {
+    modulation.targets[route.target].offset += route.depth * source_value(route.source);
+  }
+  for target in modulation.targets.iter_mut() {
+    // The block may have gone, or been replaced by one of a different
+    // kind
+    let Some(param) = target_param(control_blocks, target) else {
+      continue;
+    };
+    if *param != target.written {
+      target.base = *param;
+    }
+    *param = target.base + target.offset;
+    target.written = *param;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{run, ModDestination, ModRoute, ModSource, Modulation};
+  use crate::audio::AudioConfig;
+  use crate::engine::Engine;
+  use crate::gain::GainControlBlock;
+  use crate::state::{ControlBlock, ControlSlots};
+
+  fn scale(engine: &Engine) -> f32 {
+    match &engine.state.control_blocks[0] {
+      Some(ControlBlock::Gain(ctl)) => ctl.scale,
+      _ => panic!("no gain block"),
+    }
+  }
+
+  #[test]
+  fn modulates_around_the_latest_value() {
+    let config = AudioConfig {
+      sample_rate_hz: 44100,
+      period_frames: 64,
+    };
+    let (mut engine, _commands, _telemetry) = Engine::new(config);
+    let gain = |scale| Some(ControlBlock::Gain(GainControlBlock { scale }));
+    engine.state.control_blocks[0] = gain(1.0);
+    let mut slots = ControlSlots::new();
+    slots.insert("fx".to_string(), 0);
+    let route = ModRoute {
+      source: ModSource::Cc { controller: 1 },
+      destination: ModDestination::Param {
+        block: "fx".to_string(),
+        path: "scale".to_string(),
+      },
+      depth: 0.5,
+    };
+    engine.state.modulation = Modulation::new(&[route.clone(), route], &slots);
+
+    engine.state.midi_cc[1] = 0.5;
+    run(&mut engine.state);
+    assert_eq!(scale(&engine), 1.5);
+    engine.state.midi_cc[1] = 1.0;
+    run(&mut engine.state);
+    assert_eq!(scale(&engine), 2.0);
+
+    // someone else sets the block
+    engine.state.control_blocks[0] = gain(0.5);
+    run(&mut engine.state);
+    assert_eq!(scale(&engine), 1.5);
+
+    engine
+      .state
+      .modulation
+      .restore(&mut engine.state.control_blocks);
+    assert_eq!(scale(&engine), 0.5);
+  }
+}
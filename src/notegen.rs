@@ -8,12 +8,19 @@ pub enum NoteMode {
   Run,
   Release,
   Restrike { vel: f32 },
+  // Make room for another note, fading out quickly
+  Steal,
 }
 
 #[derive(Debug)]
 pub struct NotegenState {
   mode: NoteMode,
   ugen: UgenState,
+  pub pitch: u8,
+  // Notes started later have higher serial numbers
+  pub serial: u64,
+  stolen: bool,
+  released: bool,
 }
 
 impl NotegenState {
@@ -24,22 +31,61 @@ impl NotegenState {
     // and this is one way of accomplishing that.
     let mut note_mode = NoteMode::Run;
     mem::swap(&mut self.mode, &mut note_mode);
-    let advice = Advice { note_mode };
+    let advice = Advice {
+      note_mode,
+      ..*gen.advice
+    };
     self.ugen.run(gen.readvise(&advice), tick_s, &ctl)
   }
 
   pub fn release(&mut self) {
     self.mode = NoteMode::Release;
+    self.released = true;
   }
 
   pub fn restrike(&mut self, vel: f32) {
     self.mode = NoteMode::Restrike { vel };
+    self.released = false;
   }
 
-  pub fn new(ugen: UgenState) -> Self {
+  pub fn released(&self) -> bool {
+    self.released
+  }
+
+  // Moves the note to `pitch`, at `freq_hz`, gliding if its control
+  // block says to
+  pub fn glide_to(&mut self, pitch: u8, freq_hz: f32) {
+    self.pitch = pitch;
+    if let UgenState::ReasonableSynth(s) = &mut self.ugen {
+      s.glide_to(freq_hz);
+    }
+  }
+
+  pub fn steal(&mut self) {
+    self.mode = NoteMode::Steal;
+    self.stolen = true;
+  }
+
+  pub fn stolen(&self) -> bool {
+    self.stolen
+  }
+
+  // How loud it was at the end of the last period
+  pub fn level(&self) -> f32 {
+    match &self.ugen {
+      UgenState::ReasonableSynth(s) => s.level(),
+      _ => 0.0,
+    }
+  }
+
+  pub fn new(ugen: UgenState, pitch: u8, serial: u64) -> Self {
     NotegenState {
       ugen,
       mode: NoteMode::Run,
+      pitch,
+      serial,
+      stolen: false,
+      released: false,
     }
   }
 }
@@ -0,0 +1,63 @@
+use std::f32::consts::FRAC_PI_4;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::param::{self, Key, Params};
+use crate::state::{read_channel, ControlBlock, ControlBlocks, GenState};
+use crate::ugen::Ugen;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[derive(TS)]
+#[ts(export)]
+pub struct PanControlBlock {
+  // -1 is hard left, 0 is center, 1 is hard right
+  pub pan: f32,
+}
+
+impl Params for PanControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("pan", []) => Some(&mut self.pan),
+      _ => None,
+    }
+  }
+}
+
+// Places a mono signal (or the mono downmix of a stereo one) in the
+// stereo field.
+#[derive(Clone, Debug)]
+pub struct PanState {
+  src: usize,
+  dst: usize,
+  ci: usize,
+}
+
+impl PanState {
+  pub fn new(src: usize, dst: usize, ci: usize) -> Self {
+    PanState { src, dst, ci }
+  }
+
+  fn ctl_run(&mut self, gen: GenState, ctl: &PanControlBlock) -> bool {
+    // constant power panning
+    let theta = (ctl.pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    let (left_gain, right_gain) = (theta.cos(), theta.sin());
+    for bus_ix in 0..gen.audio_bus[0][0].len() {
+      let val = read_channel(&gen.audio_bus[self.src], 1, 0, bus_ix);
+      let dst = &mut gen.audio_bus[self.dst];
+      dst[0][bus_ix] = val * left_gain;
+      dst[1][bus_ix] = val * right_gain;
+    }
+    true
+  }
+}
+
+impl Ugen for PanState {
+  fn run(&mut self, gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool {
+    match &ctl[self.ci] {
+      Some(ControlBlock::Pan(ctl)) => self.ctl_run(gen, ctl),
+      _ => false,
+    }
+  }
+}
@@ -0,0 +1,154 @@
+use serde_json::Value;
+
+use crate::state::ControlBlock;
+use crate::validate::ValidationError;
+
+// Individual numbers inside a control block are addressed by a path
+// of field names and list indices, separated by dots, with field
+// names as they're serialized. E.g. "scale" in a Gain block,
+// "adsr.attack_s" in a Reasonable block, or "taps.1.weight" in a Low
+// block.
+
+// A path broken into its parts, so that the audio thread can follow
+// it without parsing or allocating.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Key {
+  Field(String),
+  Index(usize),
+}
+
+pub fn parse_path(path: &str) -> Vec<Key> {
+  path
+    .split('.')
+    .map(|key| match key.parse::<usize>() {
+      Ok(ix) => Key::Index(ix),
+      Err(_) => Key::Field(key.to_string()),
+    })
+    .collect()
+}
+
+// Control blocks, and the things in them, whose numbers can be changed
+// in place on the audio thread, e.g. by automation. Only f32 fields
+// are reachable this way.
+pub trait Params {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32>;
+}
+
+// Splits off the field name at the start of `path`
+pub fn field(path: &[Key]) -> Option<(&str, &[Key])> {
+  match path {
+    [Key::Field(name), rest @ ..] => Some((name.as_str(), rest)),
+    _ => None,
+  }
+}
+
+impl Params for ControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match self {
+      ControlBlock::Reasonable(ctl) => ctl.param_mut(path),
+      ControlBlock::Drum(ctl) => ctl.param_mut(path),
+      ControlBlock::Low(ctl) => ctl.param_mut(path),
+      ControlBlock::All(ctl) => ctl.param_mut(path),
+      ControlBlock::Gain(ctl) => ctl.param_mut(path),
+      ControlBlock::Reverb(ctl) => ctl.param_mut(path),
+      ControlBlock::Pan(ctl) => ctl.param_mut(path),
+      ControlBlock::Lfo(ctl) => ctl.param_mut(path),
+    }
+  }
+}
+
+// A copy of `ctl` with the number at `path` set to `value`. `name` is
+// only for error messages.
+pub fn set_param(
+  name: &str,
+  ctl: &ControlBlock,
+  path: &str,
+  value: f32,
+) -> Result<ControlBlock, ValidationError> {
+  let no_such_param = || ValidationError::NoSuchParam {
+    name: name.to_string(),
+    path: path.to_string(),
+  };
+  if !value.is_finite() {
+    return Err(ValidationError::BadParamValue {
+      name: name.to_string(),
+      path: path.to_string(),
+      value,
+    });
+  }
+  let mut json = serde_json::to_value(ctl).map_err(|_| no_such_param())?;
+  let mut field = &mut json;
+  for key in path.split('.') {
+    field = match field {
+      Value::Object(fields) => fields.get_mut(key),
+      Value::Array(items) => key.parse::<usize>().ok().and_then(|ix| items.get_mut(ix)),
+      _ => None,
+    }
+    .ok_or_else(no_such_param)?;
+  }
+  *field = match field {
+    // Integer fields, like tap positions, only take whole numbers
+    Value::Number(n) if n.is_u64() => {
+      if value.fract() != 0.0 || value < 0.0 {
+        return Err(ValidationError::BadParamValue {
+          name: name.to_string(),
+          path: path.to_string(),
+          value,
+        });
+      }
+      Value::from(value as u64)
+    },
+    Value::Number(_) => Value::from(value as f64),
+    _ => return Err(no_such_param()),
+  };
+  serde_json::from_value(json).map_err(|_| no_such_param())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_path, set_param, Params};
+  use crate::lowpass::{LowpassControlBlock, Tap, TapType};
+  use crate::state::ControlBlock;
+
+  fn low() -> ControlBlock {
+    ControlBlock::Low(LowpassControlBlock {
+      taps: vec![Tap {
+        tp: TapType::Rec,
+        pos: 1,
+        weight: 0.5,
+      }],
+    })
+  }
+
+  #[test]
+  fn sets_nested_numbers() {
+    match set_param("lp", &low(), "taps.0.weight", 0.25) {
+      Ok(ControlBlock::Low(ctl)) => assert_eq!(ctl.taps[0].weight, 0.25),
+      other => panic!("unexpected {:?}", other),
+    }
+    match set_param("lp", &low(), "taps.0.pos", 3.0) {
+      Ok(ControlBlock::Low(ctl)) => assert_eq!(ctl.taps[0].pos, 3),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn rejects_bad_paths_and_values() {
+    assert!(set_param("lp", &low(), "taps.1.weight", 0.25).is_err());
+    assert!(set_param("lp", &low(), "taps.0.tp", 0.25).is_err());
+    assert!(set_param("lp", &low(), "t", 0.25).is_err());
+    assert!(set_param("lp", &low(), "taps.0.pos", 1.5).is_err());
+  }
+
+  #[test]
+  fn reaches_numbers_in_place() {
+    let mut ctl = low();
+    *ctl.param_mut(&parse_path("taps.0.weight")).unwrap() = 0.25;
+    match &ctl {
+      ControlBlock::Low(ctl) => assert_eq!(ctl.taps[0].weight, 0.25),
+      _ => unreachable!(),
+    }
+    assert!(ctl.param_mut(&parse_path("taps.0.pos")).is_none());
+    assert!(ctl.param_mut(&parse_path("taps.1.weight")).is_none());
+  }
+}
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::automation::NamedLanes;
+use crate::midi_learn::MidiBinding;
+use crate::modulation::ModRoute;
+use crate::state::NamedControlBlocks;
+use crate::ugen::UgenSpec;
+
+// Where SavePatch and LoadPatch look for patches, relative to the
+// working directory, like ./public is for the web server.
+const PATCH_DIR: &str = "patches";
+
+// Everything needed to recreate a sound: the ugen graph and the
+// settings of every control block it uses. Stored as pretty-printed
+// JSON, so that patches diff sensibly under version control.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Patch {
+  pub specs: Vec<UgenSpec>,
+  pub control_blocks: NamedControlBlocks,
+  // Older patches have no automation
+  #[serde(default)]
+  pub lanes: NamedLanes,
+  #[serde(default)]
+  pub mod_routes: Vec<ModRoute>,
+  #[serde(default)]
+  pub midi_bindings: Vec<MidiBinding>,
+}
+
+// The file a patch called `name` lives in
+pub fn path_of_name(name: &str) -> anyhow::Result<PathBuf> {
+  path_in(Path::new(PATCH_DIR), name, "json")
+}
+
+// The file called `name` in `dir`. Names are kept to a single path
+// component, so that a web client can't read or write files outside
+// `dir`.
+pub fn path_in(dir: &Path, name: &str, extension: &str) -> anyhow::Result<PathBuf> {
+  let valid = !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+  if !valid {
+    return Err(anyhow!(
+      "bad file name {name:?}; use letters, digits, - and _"
+    ));
+  }
+  Ok(dir.join(format!("{name}.{extension}")))
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Patch> {
+  let file = File::open(path).with_context(|| format!("opening patch {}", path.display()))?;
+  let patch =
+    serde_json::from_reader(file).with_context(|| format!("parsing patch {}", path.display()))?;
+  Ok(patch)
+}
+
+pub fn save(path: &Path, patch: &Patch) -> anyhow::Result<()> {
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  let file = File::create(path).with_context(|| format!("creating patch {}", path.display()))?;
+  let mut out = BufWriter::new(file);
+  serde_json::to_writer_pretty(&mut out, patch)?;
+  writeln!(out)?;
+  out.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::path_of_name;
+
+  #[test]
+  fn patch_names_stay_in_patch_dir() {
+    assert!(path_of_name("bass-2").is_ok());
+    assert!(path_of_name("../etc/passwd").is_err());
+    assert!(path_of_name("a/b").is_err());
+    assert!(path_of_name("").is_err());
+  }
+}
@@ -1,20 +1,88 @@
+use std::f32::consts::TAU;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::consts::SAMPLE_RATE_hz;
 use crate::envelope::{Adsr, EnvState};
+use crate::midi_manager::{Mono, Polyphony};
+use crate::modulation::VoiceParam;
 use crate::notegen::NoteMode;
+use crate::param::{self, Key, Params};
+use crate::smooth::{self, Smoothed};
 use crate::state::{ControlBlock, ControlBlocks, GenState};
+use crate::tuning::Tuning;
 use crate::ugen::{Advice, Ugen};
+use crate::util;
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Clone, Serialize, Deserialize, Debug, TS)]
 #[ts(export)]
 pub struct ReasonableControlBlock {
   pub adsr: Adsr,
+  // Older patches don't have this
+  #[serde(default)]
+  pub wheels: Wheels,
+  #[serde(default)]
+  pub polyphony: Polyphony,
+  // Polyphonic unless this is set
+  #[serde(default)]
+  #[ts(optional = nullable)]
+  pub mono: Option<Mono>,
+  // 12-TET at A440 unless this is set
+  #[serde(default)]
+  #[ts(optional = nullable)]
+  pub tuning: Option<Tuning>,
 }
 
+impl Params for ReasonableControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("adsr", rest) => self.adsr.param_mut(rest),
+      ("wheels", rest) => self.wheels.param_mut(rest),
+      ("mono", rest) => self.mono.as_mut()?.param_mut(rest),
+      ("tuning", rest) => self.tuning.as_mut()?.param_mut(rest),
+      // Nothing in polyphony is an f32
+      _ => None,
+    }
+  }
+}
+
+// What the pitch bend and mod wheels do
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, TS)]
+#[ts(export)]
+pub struct Wheels {
+  // How far the pitch bend wheel bends, in semitones either way
+  pub bend_range: f32,
+  // The mod wheel adds vibrato, up to vibrato_depth semitones either
+  // way with the wheel all the way up
+  pub vibrato_hz: f32,
+  pub vibrato_depth: f32,
+}
+
+impl Default for Wheels {
+  fn default() -> Self {
+    Wheels {
+      bend_range: 2.0,
+      vibrato_hz: 5.5,
+      vibrato_depth: 0.5,
+    }
+  }
+}
+
+impl Params for Wheels {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("bend_range", []) => Some(&mut self.bend_range),
+      ("vibrato_hz", []) => Some(&mut self.vibrato_hz),
+      ("vibrato_depth", []) => Some(&mut self.vibrato_depth),
+      _ => None,
+    }
+  }
+}
+
+// How long a stolen voice takes to fade out
+const STEAL_FADE_S: f32 = 0.005;
+
 #[derive(Clone, Debug)]
 pub struct ReasonableSynthState {
   dst: usize,
@@ -23,10 +91,35 @@ pub struct ReasonableSynthState {
   env_state: EnvState,
   wavetable: Arc<Vec<f32>>,
   ci: usize,
+  // The other envelope parameters only matter at the moment they're
+  // used, but a held note sits at the sustain level.
+  sustain: Smoothed,
+  // Modulation sources of our own, see modulation.rs
+  key: f32,
+  velocity: f32,
+  amp: Smoothed,
+  // Following the wheels, so that bends and vibrato don't step
+  bend: Smoothed,
+  vibrato: Smoothed,
+  vibrato_phase: f32,
+  // In semitones from freq_hz, for mono notes that have moved
+  glide_to: f32,
+  glide: Smoothed,
+  // Fading out to make room for another note
+  stolen: bool,
+  // The amplitude it last played at
+  level: f32,
 }
 
 impl ReasonableSynthState {
-  pub fn new(dst: usize, freq_hz: f32, vel: f32, wavetable: Arc<Vec<f32>>, ci: usize) -> Self {
+  pub fn new(
+    dst: usize,
+    freq_hz: f32,
+    vel: f32,
+    velocity: f32,
+    wavetable: Arc<Vec<f32>>,
+    ci: usize,
+  ) -> Self {
     ReasonableSynthState {
       dst,
       phase: 0.0,
@@ -39,12 +132,38 @@ impl ReasonableSynthState {
       },
       wavetable,
       ci,
+      sustain: Smoothed::new(),
+      key: (freq_hz / util::freq_of_pitch(60)).log2(),
+      velocity,
+      amp: Smoothed::new(),
+      bend: Smoothed::new(),
+      vibrato: Smoothed::new(),
+      vibrato_phase: 0.0,
+      glide_to: 0.0,
+      glide: Smoothed::new(),
+      stolen: false,
+      level: 0.0,
     }
   }
 
+  pub fn level(&self) -> f32 {
+    self.level
+  }
+
+  pub fn glide_to(&mut self, freq_hz: f32) {
+    self.glide_to = 12.0 * (freq_hz / self.freq_hz).log2();
+    self.key = (freq_hz / util::freq_of_pitch(60)).log2();
+  }
+
   fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &ReasonableControlBlock) -> bool {
-    let ReasonableControlBlock { adsr } = ctl;
-    let Advice { note_mode } = gen.advice;
+    let ReasonableControlBlock {
+      adsr, wheels, mono, ..
+    } = ctl;
+    let Advice {
+      note_mode,
+      bend,
+      mod_wheel,
+    } = gen.advice;
 
     match note_mode {
       NoteMode::Release => {
@@ -61,10 +180,46 @@ impl ReasonableSynthState {
           hold: true,
         };
       },
+      NoteMode::Steal => {
+        self.stolen = true;
+        self.env_state = EnvState::Release {
+          t_s: 0.0,
+          amp: self.env_state.amp(adsr),
+        };
+      },
       NoteMode::Run => (),
     }
 
-    for out in gen.audio_bus[self.dst].iter_mut() {
+    let mut semitones = 0.0;
+    let mut amp = 1.0;
+    for route in gen
+      .voice_routes
+      .iter()
+      .filter(|route| route.index == self.ci)
+    {
+      let amount = route.amount(self.key, self.velocity);
+      match route.param {
+        VoiceParam::Pitch => semitones += amount,
+        VoiceParam::Amp => amp += amount,
+      }
+    }
+    let amp: f32 = amp.max(0.0);
+
+    let k = smooth::coefficient(tick_s, gen.ramp_s);
+    let k_glide = smooth::coefficient(tick_s, mono.map_or(0.0, |mono| mono.glide_s));
+    let sustain = gen.target(self.ci, &["adsr", "sustain"], adsr.sustain);
+    let bus = &mut gen.audio_bus[self.dst];
+    for bus_ix in 0..bus[0].len() {
+      let adsr = &Adsr {
+        sustain: self.sustain.follow(&sustain, bus_ix, k),
+        release_s: if self.stolen {
+          STEAL_FADE_S
+        } else {
+          adsr.release_s
+        },
+        ..*adsr
+      };
+
       let table_phase: f32 = self.phase * ((self.wavetable.len() - 1) as f32);
       let offset = table_phase.floor() as usize;
 
@@ -79,14 +234,24 @@ impl ReasonableSynthState {
         fpart * self.wavetable[offset + 1] + (1.0 - fpart) * self.wavetable[offset]
       };
 
-      let scale = self.env_state.amp(adsr);
-      *out += (scale as f32) * table_val;
+      let vibrato = self.vibrato.next(mod_wheel * wheels.vibrato_depth, k);
+      let bend = self.bend.next(bend * wheels.bend_range, k);
+      let wobble = vibrato * (TAU * self.vibrato_phase).sin();
+      let glide = self.glide.next(self.glide_to, k_glide);
+      let freq_hz = self.freq_hz * ((semitones + bend + wobble + glide) / 12.0).exp2();
+
+      let scale = self.env_state.amp(adsr) * self.amp.next(amp, k);
+      self.level = scale;
+      for line in bus.iter_mut() {
+        line[bus_ix] += (scale as f32) * table_val;
+      }
 
       // advance
-      self.phase += self.freq_hz / SAMPLE_RATE_hz;
+      self.phase += freq_hz * tick_s;
       if self.phase > 1. {
         self.phase -= 1.;
       }
+      self.vibrato_phase = (self.vibrato_phase + wheels.vibrato_hz * tick_s).fract();
       if !self.env_state.advance(tick_s, adsr) {
         return false;
       }
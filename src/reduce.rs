@@ -1,13 +1,14 @@
-use anyhow::anyhow;
-
-use crate::midi::Message;
-use crate::midi_manager::MidiManagerState;
+use crate::consts::NUM_KEYS;
+use crate::engine::Warning;
+use crate::midi::{Message, MOD_WHEEL, NUM_CHANNELS};
+use crate::midi_manager::{MidiManagerState, Mono, Polyphony};
 use crate::notegen::NotegenState;
-use crate::state::{get_key_state_mut, new_reasonable_of_tables, KeyState, State};
+use crate::state::{
+  get_key_state_mut, new_reasonable_of_tables, ControlBlock, ControlBlocks, KeyState, State,
+};
+use crate::tuning::{self, Tuning};
 use crate::ugen::UgenState;
-use crate::util;
 use crate::wavetables::Wavetables;
-use crate::webserver::SynthMessage;
 
 pub fn add_gen<T>(ns: &mut Vec<Option<T>>, new: T) -> usize {
   let first_free_index = ns.iter().position(|x| match x {
@@ -42,11 +43,169 @@ fn ugen_ix_of_key_state(key_state: &KeyState) -> Option<usize> {
   }
 }
 
+// Sounds `pitch` on the mono voice, which glides there from wherever
+// it was, or starts a voice if there isn't one
+fn mono_play(
+  pitch: u8,
+  retrigger: bool,
+  wavetables: &Wavetables,
+  polyphony: &Polyphony,
+  tuning: Option<&Tuning>,
+  midi_manager: &mut MidiManagerState,
+) {
+  let velocity = midi_manager.last_velocity;
+  let vel = (velocity as f32) / 1280.0;
+  // Notes the tuning leaves out don't play
+  let Some(freq) = tuning::freq(tuning, pitch) else {
+    return;
+  };
+  if let Some(voice) = midi_manager.mono_voice() {
+    let restrike = retrigger || voice.released();
+    voice.glide_to(pitch, freq);
+    if restrike {
+      voice.restrike(vel);
+    }
+    return;
+  }
+  midi_manager.make_room(polyphony, pitch);
+  let MidiManagerState { dst, ci, .. } = *midi_manager;
+  let ugen = new_reasonable_of_tables(dst, wavetables, freq, vel, (velocity as f32) / 127.0, ci);
+  midi_manager.notes_started += 1;
+  let serial = midi_manager.notes_started;
+  let ix = add_gen(
+    &mut midi_manager.notegen_state,
+    NotegenState::new(ugen, pitch, serial),
+  );
+  midi_manager.mono_voice = Some((ix, serial));
+}
+
+// Notes in mono mode. Returns whether the message has been dealt with,
+// or should go on to be handled as it would be in poly mode.
+fn mono_reducer(
+  msg: &Message,
+  wavetables: &Wavetables,
+  mono: &Mono,
+  polyphony: &Polyphony,
+  tuning: Option<&Tuning>,
+  midi_manager: &mut MidiManagerState,
+) -> bool {
+  match *msg {
+    Message::NoteOn {
+      pitch, velocity, ..
+    } => {
+      let overlapping = !midi_manager.held.is_empty();
+      midi_manager.held.retain(|&p| p != pitch);
+      midi_manager.held.push(pitch);
+      midi_manager.last_velocity = velocity;
+      if mono.pitch(&midi_manager.held) == Some(pitch) {
+        let retrigger = !(mono.legato && overlapping);
+        mono_play(
+          pitch,
+          retrigger,
+          wavetables,
+          polyphony,
+          tuning,
+          midi_manager,
+        );
+      }
+      true
+    },
+    Message::NoteOff { pitch, channel } => {
+      // A note played before mono mode was switched on
+      let key_state = get_key_state_mut(&mut midi_manager.key_state, channel, pitch);
+      if ugen_ix_of_key_state(key_state).is_some() {
+        return false;
+      }
+      let before = mono.pitch(&midi_manager.held);
+      midi_manager.held.retain(|&p| p != pitch);
+      match mono.pitch(&midi_manager.held) {
+        None => {
+          let pedal = midi_manager.pedal;
+          if let Some(voice) = midi_manager
+            .mono_voice()
+            .filter(|v| !pedal && !v.released())
+          {
+            voice.release();
+          }
+        },
+        // Back to a note still held
+        Some(next) if Some(next) != before => {
+          mono_play(
+            next,
+            !mono.legato,
+            wavetables,
+            polyphony,
+            tuning,
+            midi_manager,
+          );
+        },
+        Some(_) => (),
+      }
+      true
+    },
+    Message::PedalOff { .. } => {
+      if midi_manager.held.is_empty() {
+        if let Some(voice) = midi_manager.mono_voice().filter(|v| !v.released()) {
+          voice.release();
+        }
+      }
+      false
+    },
+    _ => false,
+  }
+}
+
 pub fn midi_reducer_inner(
   msg: &Message,
   wavetables: &Wavetables,
+  control_blocks: &ControlBlocks,
   midi_manager: &mut MidiManagerState,
-) -> anyhow::Result<()> {
+) -> Result<(), Warning> {
+  // Midi only has 128 notes, but scripts can send others
+  if let Message::NoteOn { pitch, channel, .. } | Message::NoteOff { pitch, channel } = *msg {
+    if pitch as usize >= NUM_KEYS || channel >= NUM_CHANNELS {
+      return Err(Warning::NoNote { pitch, channel });
+    }
+  }
+  let ctl = match control_blocks.get(midi_manager.ci) {
+    Some(Some(ControlBlock::Reasonable(ctl))) => Some(ctl),
+    _ => None,
+  };
+  let polyphony = ctl.map_or_else(Polyphony::default, |ctl| ctl.polyphony);
+  let tuning = ctl.and_then(|ctl| ctl.tuning.as_ref());
+  match ctl.and_then(|ctl| ctl.mono) {
+    Some(mono) => {
+      if mono_reducer(msg, wavetables, &mono, &polyphony, tuning, midi_manager) {
+        return Ok(());
+      }
+    },
+    // The note a mono voice left sounding, if it was switched off
+    // mid-note
+    None => {
+      if let Message::NoteOff { pitch, .. } = msg {
+        if midi_manager.held.contains(pitch) {
+          midi_manager.held.retain(|p| p != pitch);
+          if midi_manager.held.is_empty() {
+            if let Some(voice) = midi_manager.mono_voice() {
+              voice.release();
+            }
+          }
+          return Ok(());
+        }
+      }
+    },
+  }
+  // A new voice may need room; a restruck one already has it
+  if let Message::NoteOn { pitch, channel, .. } = msg {
+    let key_state = get_key_state_mut(&mut midi_manager.key_state, *channel, *pitch);
+    if ugen_ix_of_key_state(key_state).is_none() {
+      // Notes the tuning leaves out don't play
+      if tuning::freq(tuning, *pitch).is_none() {
+        return Ok(());
+      }
+      midi_manager.make_room(&polyphony, *pitch);
+    }
+  }
   {
     let MidiManagerState {
       ref dst,
@@ -54,6 +213,9 @@ pub fn midi_reducer_inner(
       ref mut key_state,
       ref mut notegen_state,
       ref ci,
+      ref mut bend,
+      ref mut mod_wheel,
+      ref mut notes_started,
       ..
     } = midi_manager;
     match msg {
@@ -62,16 +224,21 @@ pub fn midi_reducer_inner(
         channel,
         velocity,
       } => {
-        let pitch = *pitch;
-        let freq = util::freq_of_pitch(pitch);
+        let (pitch, channel) = (*pitch, *channel);
         // Is this ugen already being played?
-        let pre = ugen_ix_of_key_state(get_key_state_mut(key_state, pitch as usize));
+        let pre = ugen_ix_of_key_state(get_key_state_mut(key_state, channel, pitch));
         let vel = (*velocity as f32) / 1280.0;
 
         let ugen_ix = match pre {
           None => {
-            let ugen = new_reasonable_of_tables(*dst, wavetables, freq, vel, *ci);
-            add_gen(notegen_state, ugen)
+            let freq = tuning::freq(tuning, pitch).unwrap_or_default();
+            let velocity = (*velocity as f32) / 127.0;
+            let ugen = new_reasonable_of_tables(*dst, wavetables, freq, vel, velocity, *ci);
+            *notes_started += 1;
+            add_gen(
+              notegen_state,
+              NotegenState::new(ugen, pitch, *notes_started),
+            )
           },
           Some(ugen_ix) => match &mut notegen_state[ugen_ix] {
             None => panic!("Invariant Violation: expected key_state pointed to live ugen"),
@@ -81,20 +248,20 @@ pub fn midi_reducer_inner(
             },
           },
         };
-        *get_key_state_mut(key_state, pitch.into()) = KeyState::On { ugen_ix };
+        *get_key_state_mut(key_state, channel, pitch) = KeyState::On { ugen_ix };
       },
       Message::NoteOff { pitch, channel } => {
-        let pitch = *pitch;
-        let pre = ugen_ix_of_key_state(get_key_state_mut(key_state, pitch as usize));
+        let (pitch, channel) = (*pitch, *channel);
+        let pre = ugen_ix_of_key_state(get_key_state_mut(key_state, channel, pitch));
 
         match pre {
-          None => println!("warning: NoteOff {} on a ugen already off", pitch),
+          None => return Err(Warning::NoteAlreadyOff { pitch, channel }),
           Some(ugen_ix) => {
             if *pedal {
-              *get_key_state_mut(key_state, pitch.into()) = KeyState::Held { ugen_ix };
+              *get_key_state_mut(key_state, channel, pitch) = KeyState::Held { ugen_ix };
             } else {
               release_maybe_notegen(&mut notegen_state[ugen_ix]);
-              *get_key_state_mut(key_state, pitch.into()) = KeyState::Off;
+              *get_key_state_mut(key_state, channel, pitch) = KeyState::Off;
             }
           },
         }
@@ -102,52 +269,77 @@ pub fn midi_reducer_inner(
       Message::PedalOff { .. } => {
         *pedal = false;
         // Release all pedal-held ugens
-
-        let mut ugen_ixs: Vec<usize> = vec![];
-
         for ks in key_state.iter_mut() {
-          match ks {
-            KeyState::Held { ugen_ix } => {
-              ugen_ixs.push(*ugen_ix);
-              *ks = KeyState::Off;
-            },
-            _ => (),
+          if let KeyState::Held { ugen_ix } = *ks {
+            release_maybe_notegen(&mut notegen_state[ugen_ix]);
+            *ks = KeyState::Off;
           }
         }
-        for ugen_ix in ugen_ixs.iter() {
-          release_maybe_notegen(&mut notegen_state[*ugen_ix]);
-        }
       },
       Message::PedalOn { .. } => {
         *pedal = true;
       },
+      Message::PitchBend { value, .. } => {
+        *bend = (*value as f32) / 8192.0;
+      },
+      Message::ControlChange {
+        controller: MOD_WHEEL,
+        value,
+        ..
+      } => {
+        *mod_wheel = (*value as f32) / 127.0;
+      },
+      // Other controllers are modulation sources; see midi_reducer
+      Message::ControlChange { .. } => (),
+      Message::PolyAftertouch { .. }
+      | Message::ProgramChange { .. }
+      | Message::ChannelAftertouch { .. } => (),
     }
     Ok(())
   }
 }
 
-// Could have this function return pure data that represents the
-// change, then have subsequent function carry it out, so that we hold
-// state lock for shorter duration.
-pub fn midi_reducer(msg: &Message, state: &mut State) -> anyhow::Result<()> {
+// Runs on the audio thread, see EngineCommand::Midi
+pub fn midi_reducer(msg: &Message, state: &mut State) -> Result<(), Warning> {
+  // Controllers are modulation sources, whether or not there's a midi
+  // manager to play notes
+  if let Message::ControlChange {
+    controller, value, ..
+  } = msg
+  {
+    if let Some(cc) = state.midi_cc.get_mut(*controller as usize) {
+      *cc = (*value as f32) / 127.0;
+    }
+    if *controller != MOD_WHEEL {
+      return Ok(());
+    }
+  }
+
   let State {
-    ref websocket,
     fixed_ugens,
     wavetables,
+    control_blocks,
     ..
   } = state;
 
-  if let Some(ws) = websocket {
-    ws.try_send(SynthMessage::Midi { msg: msg.clone() })?
+  // Every midi manager on the message's channel gets it, so that
+  // several can be played at once, each on its own channels
+  let channel = msg.channel();
+  let mut played = false;
+  let mut warning = None;
+  for node in fixed_ugens.iter_mut().filter(|node| !node.fading_out()) {
+    if let UgenState::MidiManager(midi_manager) = &mut *node.ugen {
+      if midi_manager.plays(channel) {
+        // One manager's warning doesn't keep the others from playing
+        if let Err(w) = midi_reducer_inner(msg, wavetables, control_blocks, midi_manager) {
+          warning = Some(w);
+        }
+        played = true;
+      }
+    }
   }
-
-  let midi_manager = fixed_ugens
-    .iter_mut()
-    .find_map(|ugen| match ugen {
-      UgenState::MidiManager(m) => Some(m),
-      _ => None,
-    })
-    .map_or_else(|| Err(anyhow!("couldn't find midi manager")), Ok)?;
-
-  midi_reducer_inner(msg, wavetables, midi_manager)
+  if !played {
+    return Err(Warning::NoMidiManager { channel });
+  }
+  warning.map_or(Ok(()), Err)
 }
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::audio::{AudioConfig, CHANNELS};
+use crate::control::ControlState;
+use crate::engine::{Engine, Telemetry};
+use crate::midi::Message;
+use crate::sequencer::{sequencer_step, SEQ_PATTERN_LEN, SEQ_STEP_MS};
+use crate::synth::interleave_out;
+use crate::wav::{WavFormat, WavWriter};
+use crate::webserver::WebMessage;
+
+#[derive(Parser, Debug, Clone)]
+pub struct RenderArgs {
+  // Output WAV file
+  #[arg(short, long)]
+  output: PathBuf,
+
+  // Length of the render, in seconds
+  #[arg(short, long)]
+  duration_s: f32,
+
+  // JSON file containing a list of timed events to play
+  #[arg(short, long)]
+  script: Option<PathBuf>,
+
+  // Sample format of the output file
+  #[arg(short, long, value_enum, default_value_t = WavFormat::S16)]
+  format: WavFormat,
+}
+
+// Something that can happen during an offline render.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "t")]
+#[serde(rename_all = "camelCase")]
+pub enum ScriptAction {
+  Midi { msg: Message },
+  Web { msg: WebMessage },
+}
+
+// A script is a JSON list of these, e.g.
+// [{ "time_s": 0.5, "t": "midi", "msg": { "t": "noteOn", "pitch": 60, "channel": 0, "velocity": 100 } }]
+#[derive(Deserialize, Debug)]
+pub struct ScriptEvent {
+  pub time_s: f32,
+  #[serde(flatten)]
+  pub action: ScriptAction,
+}
+
+pub fn load_script(path: &PathBuf) -> anyhow::Result<Vec<ScriptEvent>> {
+  let file = File::open(path).with_context(|| format!("opening script {}", path.display()))?;
+  let mut events: Vec<ScriptEvent> =
+    serde_json::from_reader(file).with_context(|| format!("parsing script {}", path.display()))?;
+  events.sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+  Ok(events)
+}
+
+fn apply_action(action: ScriptAction, s: &mut ControlState) {
+  match action {
+    ScriptAction::Midi { msg } => s.send_midi(msg),
+    ScriptAction::Web { msg } => {
+      if let Err(e) = crate::reduce_web_message(msg, s) {
+        println!("Warning: {}", e);
+      }
+    },
+  }
+}
+
+// Runs the synth as fast as it will go, with no sound card involved.
+// Events are applied at the start of the buffer containing their
+// timestamp, and the sequencer is clocked by sample time rather
+// than wall time, so renders are reproducible.
+pub fn render(
+  config: AudioConfig,
+  patch: Option<&PathBuf>,
+  args: &RenderArgs,
+) -> anyhow::Result<()> {
+  let events = match &args.script {
+    None => vec![],
+    Some(path) => load_script(path)?,
+  };
+
+  let mono_buf_size = config.period_frames;
+  let sample_rate_hz = config.sample_rate_hz as f32;
+  let (mut engine, commands, mut telemetry) = Engine::new(config);
+  let mut control = ControlState::new(config, engine.state.wavetables.clone(), commands);
+  crate::init_control_state(&mut control, patch)?;
+  engine.process_commands();
+
+  let file = File::create(&args.output)
+    .with_context(|| format!("creating output {}", args.output.display()))?;
+  let mut wav = WavWriter::new(
+    BufWriter::new(file),
+    CHANNELS as u16,
+    config.sample_rate_hz,
+    args.format,
+  )?;
+
+  let total_frames = (args.duration_s * sample_rate_hz) as usize;
+  let seq_step_frames = ((SEQ_STEP_MS as f32 / 1000.0 * sample_rate_hz) as usize).max(1);
+  let mut events = events.into_iter().peekable();
+  let mut frame: usize = 0;
+  let mut next_seq_frame: usize = 0;
+  let mut seq_pos: usize = 0;
+  let mut buf = vec![0f32; mono_buf_size * (CHANNELS as usize)];
+
+  while frame < total_frames {
+    let buf_end_s = ((frame + mono_buf_size) as f32) / sample_rate_hz;
+    // Commands are processed as soon as they're sent, so that a burst
+    // of events can't overflow the command queue.
+    while let Some(event) = events.next_if(|e| e.time_s < buf_end_s) {
+      apply_action(event.action, &mut control);
+      engine.process_commands();
+    }
+    while next_seq_frame < frame + mono_buf_size {
+      sequencer_step(&mut control, seq_pos);
+      engine.process_commands();
+      seq_pos = (seq_pos + 1) % SEQ_PATTERN_LEN;
+      next_seq_frame += seq_step_frames;
+    }
+
+    engine.render();
+    interleave_out(&engine.state, &mut buf);
+    while let Ok(t) = telemetry.pop() {
+      if let Telemetry::Warning(w) = t {
+        println!("Warning: {}", w);
+      }
+    }
+    control.flush_commands();
+
+    let frames = mono_buf_size.min(total_frames - frame);
+    wav.write_samples(&buf[..frames * (CHANNELS as usize)])?;
+    frame += frames;
+  }
+
+  wav.finish()?;
+  println!(
+    "Rendered {:.2}s to {}",
+    args.duration_s,
+    args.output.display()
+  );
+  Ok(())
+}
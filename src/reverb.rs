@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use ts_rs::TS;
 
+use crate::param::{self, Key, Params};
+use crate::smooth::{self, Smoothed};
 use crate::state::{ControlBlock, ControlBlocks, GenState};
 use crate::ugen::Ugen;
 
@@ -22,20 +24,41 @@ pub struct Tap {
   pub weight: f32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
 #[ts(export)]
 pub struct ReverbControlBlock {
   room_size: f32,
   wet: f32,
+  // Stereo spread of the wet signal, 0 is mono, 1 is widest. Blocks
+  // from before there was a width get 0, which sounds as they did.
+  #[serde(default)]
+  width: f32,
+}
+
+impl Params for ReverbControlBlock {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("roomSize", []) => Some(&mut self.room_size),
+      ("wet", []) => Some(&mut self.wet),
+      ("width", []) => Some(&mut self.width),
+      _ => None,
+    }
+  }
 }
 
+// The output used to be downmixed to mono as (left + right) / 0.5;
+// keep the same loudness now that left and right are separate.
+const OUTPUT_GAIN: f32 = 4.0;
+
 pub struct ReverbState {
   src: usize,
   dst: usize,
   freeverb_state: Freeverb,
   ci: usize,
+  wet: Smoothed,
+  width: Smoothed,
 }
 
 impl Debug for ReverbState {
@@ -45,8 +68,8 @@ impl Debug for ReverbState {
 }
 
 impl ReverbState {
-  pub fn new(src: usize, dst: usize, ci: usize) -> Self {
-    let mut freeverb_state = Freeverb::new(44100);
+  pub fn new(src: usize, dst: usize, ci: usize, sample_rate_hz: u32) -> Self {
+    let mut freeverb_state = Freeverb::new(sample_rate_hz as usize);
     freeverb_state.set_room_size(0.2f64);
     freeverb_state.set_dry(0.9f64);
     freeverb_state.set_wet(0.1f64);
@@ -55,19 +78,35 @@ impl ReverbState {
       dst,
       freeverb_state,
       ci,
+      wet: Smoothed::new(),
+      width: Smoothed::new(),
     }
   }
 
   fn ctl_run(&mut self, gen: GenState, tick_s: f32, ctl: &ReverbControlBlock) -> bool {
-    let freeverb_state = &mut self.freeverb_state;
-    freeverb_state.set_room_size((0.01 + 0.98 * ctl.room_size) as f64);
-    freeverb_state.set_dry((1.0 - ctl.wet) as f64);
-    freeverb_state.set_wet(ctl.wet as f64);
-
-    for bus_ix in 0..gen.audio_bus[0].len() {
-      let inv = gen.audio_bus[self.src][bus_ix];
-      let (left, right) = self.freeverb_state.tick((inv as f64, inv as f64));
-      gen.audio_bus[self.dst][bus_ix] = ((left + right) / 0.5) as f32;
+    // Room size is less prone to zipper noise, and costlier to
+    // change, so it isn't smoothed
+    self
+      .freeverb_state
+      .set_room_size((0.01 + 0.98 * ctl.room_size) as f64);
+
+    let k = smooth::coefficient(tick_s, gen.ramp_s);
+    let wet_target = gen.target(self.ci, &["wet"], ctl.wet);
+    let width_target = gen.target(self.ci, &["width"], ctl.width);
+    for bus_ix in 0..gen.audio_bus[0][0].len() {
+      let wet = self.wet.follow(&wet_target, bus_ix, k);
+      let width = self.width.follow(&width_target, bus_ix, k);
+      let freeverb_state = &mut self.freeverb_state;
+      freeverb_state.set_dry((1.0 - wet) as f64);
+      freeverb_state.set_wet(wet as f64);
+      freeverb_state.set_width(width as f64);
+
+      let src = &gen.audio_bus[self.src];
+      let inv = (src[0][bus_ix] as f64, src[1][bus_ix] as f64);
+      let (left, right) = self.freeverb_state.tick(inv);
+      let dst = &mut gen.audio_bus[self.dst];
+      dst[0][bus_ix] = (left as f32) * OUTPUT_GAIN;
+      dst[1][bus_ix] = (right as f32) * OUTPUT_GAIN;
     }
     true
   }
@@ -81,3 +120,14 @@ impl Ugen for ReverbState {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::ReverbControlBlock;
+
+  #[test]
+  fn width_defaults_to_mono() {
+    let ctl: ReverbControlBlock = serde_json::from_str(r#"{"roomSize": 0.5, "wet": 0.3}"#).unwrap();
+    assert_eq!(ctl.width, 0.0);
+  }
+}
@@ -1,7 +1,8 @@
+use crate::control::{ControlGuard, ControlState};
 use crate::drum::DrumSynthState;
-use crate::state::{State, StateGuard, DEFAULT_DRUM_CONTROL_BLOCK};
+use crate::engine::EngineCommand;
+use crate::state::drum_control_block;
 use crate::ugen::UgenState;
-use crate::ugen_group::UgenGroupState;
 use crate::util::depoison;
 use crate::wavetables::Wavetables;
 use std::sync::MutexGuard;
@@ -14,53 +15,43 @@ pub struct Sequencer {
 
 pub const SEQ_NUM_INSTRS: usize = 3;
 pub const SEQ_PATTERN_LEN: usize = 16;
+pub const SEQ_STEP_MS: u64 = 125;
 
 pub fn new_drum(wavetables: &Wavetables, ctl: usize) -> UgenState {
   UgenState::DrumSynth(DrumSynthState::new(wavetables.noise_wavetable.clone(), ctl))
 }
 
-fn sequencer_loop_inner(col: &Vec<bool>, wavetables: &Wavetables, group: &mut UgenGroupState) {
-  if col[0] {
-    group.add(new_drum(wavetables, DEFAULT_DRUM_CONTROL_BLOCK + 0));
+// Advance the sequencer by one column, sending the engine a drum for
+// each instrument that's on in column `pos`. Looped automation starts
+// again with the pattern.
+pub fn sequencer_step(s: &mut ControlState, pos: usize) {
+  if pos == 0 {
+    s.send(EngineCommand::PatternStart);
   }
-  if col[1] {
-    group.add(new_drum(wavetables, DEFAULT_DRUM_CONTROL_BLOCK + 1));
-  }
-  if col[2] {
-    group.add(new_drum(wavetables, DEFAULT_DRUM_CONTROL_BLOCK + 2));
+  for inst in 0..SEQ_NUM_INSTRS {
+    if s.sequencer.tab[pos][inst] {
+      // No control block, no drum
+      if let Some(drum) = s.new_drum(&drum_control_block(inst)) {
+        s.send(EngineCommand::AddToGroup(Box::new(drum)));
+      }
+    }
   }
 }
 
-pub fn sequencer_loop(sg: StateGuard) -> anyhow::Result<()> {
+pub fn sequencer_loop(cg: ControlGuard) -> anyhow::Result<()> {
   let mut pos: usize = 0;
   loop {
     {
-      let mut s: MutexGuard<State> = depoison(sg.lock())?;
+      let mut s: MutexGuard<ControlState> = depoison(cg.lock())?;
       if !s.going {
         break;
       }
 
-      let State {
-        fixed_ugens,
-        wavetables,
-        sequencer,
-        ..
-      } = &mut *s;
-
-      let maybe_group = fixed_ugens.iter_mut().find_map(|ugen| match ugen {
-        UgenState::UgenGroup(group) => Some(group),
-        _ => None,
-      });
-
-      if let Some(group) = maybe_group {
-        sequencer_loop_inner(&sequencer.tab[pos], wavetables, group);
-      } else {
-        println!("WARNING: didn't find sequencer ugen group where we expected it");
-      }
+      sequencer_step(&mut s, pos);
 
       pos = (pos + 1) % SEQ_PATTERN_LEN;
     }
-    std::thread::sleep(std::time::Duration::from_millis(125));
+    std::thread::sleep(std::time::Duration::from_millis(SEQ_STEP_MS));
   }
   Ok(())
 }
@@ -73,7 +64,13 @@ impl Sequencer {
     sequencer
   }
 
+  // See validate::check_sequencer_step
   pub fn set(&mut self, inst: usize, pat: usize, on: bool) {
     self.tab[pat][inst] = on;
   }
+
+  // Indexed by [pat][inst]
+  pub fn tab(&self) -> &Vec<Vec<bool>> {
+    &self.tab
+  }
 }
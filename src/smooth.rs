@@ -0,0 +1,69 @@
+use crate::automation::Target;
+
+// Control block values change a buffer at a time, which is audible as
+// zipper noise when e.g. a gain is dragged around. Ugens that care
+// follow the value with a Smoothed instead, which glides towards it a
+// little every sample.
+
+// The per-sample coefficient for a Smoothed that gets most of the way
+// (1 - 1/e) to where it's going in `ramp_s`. Work it out once a buffer.
+pub fn coefficient(tick_s: f32, ramp_s: f32) -> f32 {
+  if ramp_s <= 0.0 {
+    return 1.0;
+  }
+  1.0 - (-tick_s / ramp_s).exp()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Smoothed {
+  // None until we've seen the first value, which we jump straight to
+  value: Option<f32>,
+}
+
+impl Smoothed {
+  pub fn new() -> Self {
+    Smoothed { value: None }
+  }
+
+  // Advances one sample, `ix` frames into the period, towards a number
+  // in a control block. One that a lane moves is followed exactly, as
+  // the lane already glides.
+  pub fn follow(&mut self, target: &Target, ix: usize, coefficient: f32) -> f32 {
+    let coefficient = if target.automated() { 1.0 } else { coefficient };
+    self.next(target.at(ix), coefficient)
+  }
+
+  // Advances one sample towards target
+  pub fn next(&mut self, target: f32, coefficient: f32) -> f32 {
+    let value = match self.value {
+      None => target,
+      Some(value) => value + (target - value) * coefficient,
+    };
+    self.value = Some(value);
+    value
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{coefficient, Smoothed};
+
+  #[test]
+  fn glides_towards_target() {
+    let k = coefficient(1.0 / 1000.0, 0.01);
+    let mut s = Smoothed::new();
+    assert_eq!(s.next(1.0, k), 1.0);
+    let first = s.next(0.0, k);
+    assert!(first < 1.0 && first > 0.9);
+    for _ in 0..8 {
+      s.next(0.0, k);
+    }
+    // after the ramp time, about 1/e of the way left to go
+    let v = s.next(0.0, k);
+    assert!(v > 0.3 && v < 0.4);
+
+    let mut instant = Smoothed::new();
+    instant.next(1.0, coefficient(1.0 / 1000.0, 0.0));
+    assert_eq!(instant.next(0.0, coefficient(1.0 / 1000.0, 0.0)), 0.0);
+  }
+}
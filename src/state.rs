@@ -1,21 +1,24 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
 
 use crate::allpass::AllpassControlBlock;
-use crate::consts::{AUDIO_BUS_LENGTH, BOTTOM_NOTE};
+use crate::audio::AudioConfig;
+use crate::automation::{self, Automation, Target};
+use crate::consts::{DEFAULT_PARAM_RAMP_s, AUDIO_BUS_LENGTH, BUS_CHANNELS, NUM_KEYS};
 use crate::drum::DrumControlBlock;
+use crate::engine::{Telemetry, TelemetryProducer};
 use crate::gain::GainControlBlock;
+use crate::lfo::LfoControlBlock;
 use crate::lowpass::LowpassControlBlock;
-use crate::notegen::NotegenState;
+use crate::modulation::{Modulation, VoiceRoute};
+use crate::pan::PanControlBlock;
 use crate::reasonable_synth::{ReasonableControlBlock, ReasonableSynthState};
 use crate::reverb::ReverbControlBlock;
-use crate::sequencer::Sequencer;
 use crate::ugen::{Advice, UgenState, UgensState};
 use crate::wavetables::Wavetables;
-use crate::webserver::SynthMessage;
 use ts_rs::TS;
 
 // XXX move to midi manager or reduce
@@ -26,7 +29,7 @@ pub enum KeyState {
   Held { ugen_ix: usize }, // only on because pedal held
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(tag = "t")]
 #[derive(TS)]
 #[ts(export)]
@@ -37,20 +40,85 @@ pub enum ControlBlock {
   All(AllpassControlBlock),
   Gain(GainControlBlock),
   Reverb(ReverbControlBlock),
+  Pan(PanControlBlock),
+  Lfo(LfoControlBlock),
 }
 
+impl ControlBlock {
+  // The serialized tag, e.g. "Low"
+  pub fn kind(&self) -> &'static str {
+    match self {
+      ControlBlock::Reasonable(_) => "Reasonable",
+      ControlBlock::Drum(_) => "Drum",
+      ControlBlock::Low(_) => "Low",
+      ControlBlock::All(_) => "All",
+      ControlBlock::Gain(_) => "Gain",
+      ControlBlock::Reverb(_) => "Reverb",
+      ControlBlock::Pan(_) => "Pan",
+      ControlBlock::Lfo(_) => "Lfo",
+    }
+  }
+}
+
+// The engine's control blocks, indexed by slot. Ugens refer to control
+// blocks by slot, so that the audio thread never has to look up a name.
 pub type ControlBlocks = Vec<Option<ControlBlock>>;
 
-/// Outer vector is list of different busses. Inner vectors each
-/// contain one monophonic buffer's worth of audio on
-/// each bus.
-pub type AudioBusses = Vec<Vec<f32>>;
+// Control blocks as clients see them, by name.
+pub type NamedControlBlocks = BTreeMap<String, ControlBlock>;
+
+// Which slot of ControlBlocks each named control block lives in
+pub type ControlSlots = HashMap<String, usize>;
+
+/// Outer vector is list of channels, BUS_CHANNELS long. Inner vectors
+/// each contain one monophonic buffer's worth of audio on that channel.
+pub type AudioBus = Vec<Vec<f32>>;
+
+/// List of different busses.
+pub type AudioBusses = Vec<AudioBus>;
+
+/// Clamps a channel count requested by a UgenSpec to something we
+/// can actually process.
+pub fn ugen_channels(requested: Option<usize>) -> usize {
+  requested.unwrap_or(BUS_CHANNELS).clamp(1, BUS_CHANNELS)
+}
+
+/// A ugen that processes `channels` channels of audio, with
+/// `channels` possibly less than BUS_CHANNELS, reads its channel `ch`
+/// as the average of the bus channels that fold onto it. So e.g. a
+/// mono ugen hears the average of left and right.
+pub fn read_channel(bus: &AudioBus, channels: usize, ch: usize, ix: usize) -> f32 {
+  if channels == BUS_CHANNELS {
+    return bus[ch][ix];
+  }
+  let mut sum = 0.;
+  let mut n = 0;
+  for bus_ch in (ch..BUS_CHANNELS).step_by(channels) {
+    sum += bus[bus_ch][ix];
+    n += 1;
+  }
+  sum / (n as f32)
+}
+
+/// Converse of read_channel: channel `ch` of a `channels`-channel
+/// ugen is written to every bus channel that folds onto it.
+pub fn write_channel(bus: &mut AudioBus, channels: usize, ch: usize, ix: usize, val: f32) {
+  for bus_ch in (ch..BUS_CHANNELS).step_by(channels) {
+    bus[bus_ch][ix] = val;
+  }
+}
 
 #[derive(Debug)]
 pub struct GenState<'a> {
   pub audio_bus: &'a mut AudioBusses,
-  pub websocket: &'a mut Option<tokio::sync::mpsc::Sender<SynthMessage>>,
+  pub telemetry: &'a mut TelemetryProducer,
   pub advice: &'a Advice,
+  // How quickly smoothed parameters follow their control blocks
+  pub ramp_s: f32,
+  // Modulation for voices to apply to themselves
+  pub voice_routes: &'a [VoiceRoute],
+  // For smoothed numbers to follow their lanes; see automation::Target
+  pub automation: &'a Automation,
 }
 
 impl<'a> GenState<'a> {
@@ -63,11 +131,18 @@ impl<'a> GenState<'a> {
   pub fn reborrow<'b>(&'b mut self) -> GenState<'b> {
     GenState {
       audio_bus: self.audio_bus,
-      websocket: self.websocket,
+      telemetry: self.telemetry,
       advice: self.advice,
+      ramp_s: self.ramp_s,
+      voice_routes: self.voice_routes,
+      automation: self.automation,
     }
   }
 
+  pub fn target(&self, index: usize, path: &[&str], value: f32) -> Target<'a> {
+    automation::target(self.automation, index, path, value)
+  }
+
   pub fn readvise<'b>(&'b mut self, advice: &'b Advice) -> GenState<'b> {
     GenState {
       advice,
@@ -100,50 +175,68 @@ impl<T> Debug for NoDebug<T> {
   }
 }
 
+// Everything the audio thread owns. See engine.rs for how the rest of
+// the world talks to it.
 #[derive(Debug)]
 pub struct State {
   pub going: bool,
+  pub audio_config: AudioConfig,
 
   // audio bus
   pub audio_bus: AudioBusses,
-  pub websocket: Option<tokio::sync::mpsc::Sender<SynthMessage>>,
+  pub telemetry: TelemetryProducer,
 
   pub fixed_ugens: UgensState,
 
-  pub write_to_file: bool,
-
   pub control_blocks: ControlBlocks,
   pub wavetables: Wavetables,
-  pub sequencer: Sequencer,
+  pub param_ramp_s: f32,
+
+  // Frames rendered so far; the clock automation runs on
+  pub frame: u64,
+  pub automation: Automation,
+
+  // The latest value of every midi controller, from 0 to 1
+  pub midi_cc: [f32; 128],
+  pub modulation: Modulation,
 }
 
-pub type StateGuard = Arc<Mutex<State>>;
+// How many control block slots the engine starts out with. When
+// clients want more, ControlState sends the engine a bigger vector.
+pub const INITIAL_CONTROL_BLOCKS: usize = 16;
+
+pub fn new_control_blocks(len: usize) -> ControlBlocks {
+  let mut control_blocks: ControlBlocks = Vec::with_capacity(len);
+  control_blocks.resize_with(len, || None);
+  control_blocks
+}
 
-pub const DEFAULT_DRUM_CONTROL_BLOCK: usize = 10;
-pub const NUM_CONTROL_BLOCKS: usize = 16;
+// The control block played by drum `inst`, e.g. by the sequencer
+pub fn drum_control_block(inst: usize) -> String {
+  format!("drum{inst}")
+}
 
 impl State {
-  pub fn new(buf_size: usize) -> State {
-    let mut control_blocks: ControlBlocks = Vec::with_capacity(NUM_CONTROL_BLOCKS);
-    // XXX should be more dynamic, but currently there's too much
-    // hardcoded reading from ctl block vector in individual ugens
-    control_blocks.resize_with(NUM_CONTROL_BLOCKS, || None);
-
+  pub fn new(audio_config: AudioConfig, telemetry: TelemetryProducer) -> State {
     State {
       going: true,
-      sequencer: Sequencer::new(),
+      audio_config,
       fixed_ugens: vec![],
-      control_blocks,
-      write_to_file: true,
+      control_blocks: new_control_blocks(INITIAL_CONTROL_BLOCKS),
       wavetables: Wavetables::new(),
-      audio_bus: vec![vec![0.; buf_size]; AUDIO_BUS_LENGTH],
-      websocket: None,
+      param_ramp_s: DEFAULT_PARAM_RAMP_s,
+      frame: 0,
+      automation: vec![],
+      midi_cc: [0.0; 128],
+      modulation: Modulation::default(),
+      audio_bus: vec![vec![vec![0.; audio_config.period_frames]; BUS_CHANNELS]; AUDIO_BUS_LENGTH],
+      telemetry,
     }
   }
 
-  // XXX move to midi manager somehow?
-  pub fn new_drum(&self, ctl: usize) -> UgenState {
-    crate::sequencer::new_drum(&self.wavetables, ctl)
+  // If the queue is full, the telemetry is dropped.
+  pub fn send_telemetry(&mut self, t: Telemetry) {
+    let _ = self.telemetry.push(t);
   }
 }
 
@@ -154,19 +247,37 @@ pub fn new_reasonable_of_tables(
   wavetables: &Wavetables,
   freq_hz: f32,
   vel: f32,
+  velocity: f32,
   ci: usize,
-) -> NotegenState {
-  NotegenState::new(UgenState::ReasonableSynth(ReasonableSynthState::new(
+) -> UgenState {
+  UgenState::ReasonableSynth(ReasonableSynthState::new(
     dst,
     freq_hz,
     vel,
+    velocity,
     wavetables.sin_wavetable.clone(),
     ci,
-  )))
+  ))
 }
 
 // XXX move to MIDI manager maybe?
 
-pub fn get_key_state_mut(key_state: &mut Vec<KeyState>, pitch: usize) -> &mut KeyState {
-  &mut key_state[pitch - (BOTTOM_NOTE as usize)]
+// `channel` and `pitch` should already have been checked to be ones
+// midi has; see midi_reducer_inner
+pub fn get_key_state_mut(key_state: &mut [KeyState], channel: u8, pitch: u8) -> &mut KeyState {
+  &mut key_state[channel as usize * NUM_KEYS + pitch as usize]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{read_channel, write_channel};
+
+  #[test]
+  fn mono_ugen_downmixes_and_upmixes() {
+    let mut bus = vec![vec![1.0, 0.0], vec![0.0, 0.5]];
+    assert_eq!(read_channel(&bus, 1, 0, 0), 0.5);
+    assert_eq!(read_channel(&bus, 2, 1, 1), 0.5);
+    write_channel(&mut bus, 1, 0, 1, 0.25);
+    assert_eq!(bus, vec![vec![1.0, 0.25], vec![0.0, 0.25]]);
+  }
 }
@@ -1,4 +1,5 @@
-use crate::consts::SAMPLE_RATE_hz;
+use crate::consts::{BUS_CHANNELS, BUS_OUT};
+use crate::engine::{Garbage, Telemetry};
 use crate::notegen::NoteMode;
 use crate::state::{GenState, State};
 use crate::ugen::{Advice, Ugen};
@@ -15,29 +16,81 @@ impl Synth {
   pub fn synth_buf(self: &mut Synth, s: &mut State) {
     let State {
       audio_bus,
-      websocket,
+      telemetry,
+      audio_config,
+      param_ramp_s,
+      modulation,
+      automation,
       ..
     } = s;
+    let tick_s = audio_config.tick_s();
 
     // clear the audio busses
-    for line in audio_bus.iter_mut() {
-      for m in line.iter_mut() {
-        *m = 0.;
+    for bus in audio_bus.iter_mut() {
+      for line in bus.iter_mut() {
+        for m in line.iter_mut() {
+          *m = 0.;
+        }
       }
     }
 
     let advice = &Advice {
       note_mode: NoteMode::Run,
+      bend: 0.0,
+      mod_wheel: 0.0,
     };
 
-    for mut ugen in s.fixed_ugens.iter_mut() {
+    for node in s.fixed_ugens.iter_mut() {
+      if let Some(fade) = &mut node.fade {
+        fade.save(audio_bus);
+      }
       let gen_state = GenState {
         audio_bus,
-        websocket,
+        telemetry,
         advice,
+        ramp_s: *param_ramp_s,
+        voice_routes: &modulation.voice_routes,
+        automation,
       };
       // XXX This discards the boolean returned by run
-      ugen.run(gen_state, 1.0 / SAMPLE_RATE_hz, &s.control_blocks);
+      node.ugen.run(gen_state, tick_s, &s.control_blocks);
+      if let Some(fade) = &mut node.fade {
+        fade.mix(audio_bus);
+      }
+    }
+
+    finish_fades(s);
+  }
+}
+
+// Ugens that have finished fading out leave the graph, and those that
+// have finished fading in stop fading.
+fn finish_fades(s: &mut State) {
+  let mut ix = 0;
+  while ix < s.fixed_ugens.len() {
+    let node = &mut s.fixed_ugens[ix];
+    match node.fade.take_if(|fade| fade.done()) {
+      Some(fade) if fade.fading_out() => {
+        let node = s.fixed_ugens.remove(ix);
+        s.send_telemetry(Telemetry::Garbage(Garbage::Node(node)));
+        s.send_telemetry(Telemetry::Garbage(Garbage::Fade(fade)));
+      },
+      Some(fade) => {
+        s.send_telemetry(Telemetry::Garbage(Garbage::Fade(fade)));
+        ix += 1;
+      },
+      None => ix += 1,
+    }
+  }
+}
+
+// Copy the output bus into an interleaved buffer, as sound cards and
+// WAV files want it.
+pub fn interleave_out(s: &State, buf: &mut [f32]) {
+  let out = &s.audio_bus[BUS_OUT];
+  for (ix, frame) in buf.chunks_mut(BUS_CHANNELS).enumerate() {
+    for (ch, samp) in frame.iter_mut().enumerate() {
+      *samp = out[ch][ix];
     }
   }
 }
@@ -0,0 +1,356 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::consts::NUM_KEYS;
+use crate::param::{self, Key, Params};
+use crate::util;
+
+// Where LoadTuning looks for Scala files, relative to the working
+// directory, like PATCH_DIR is for patches.
+const TUNING_DIR: &str = "tunings";
+
+// Lanes and routes can move reference_hz anywhere finite, so notes
+// play from at least this
+const MIN_REFERENCE_HZ: f32 = 1e-3;
+
+// The most a map can step through the scale at once, which keeps
+// degrees well inside an i32 for every key
+const MAX_DEGREE: usize = 1024;
+
+// A scale and a keyboard mapping, as in Scala's .scl and .kbm files
+// (see https://www.huygens-fokker.org/scala/scl_format.html and
+// help.htm#mappings). Without one, notes are 12-TET at A440.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Tuning {
+  // In cents: every degree of the scale but the first, which is 0, and
+  // then the interval the scale repeats at. Empty for 12-TET.
+  #[serde(default)]
+  pub scale: Vec<f32>,
+  #[serde(default)]
+  pub keyboard: KeyboardMap,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct KeyboardMap {
+  // Notes outside these are silent
+  pub first: u8,
+  pub last: u8,
+  // The note that plays the first degree of the scale
+  pub middle: u8,
+  // The note that plays at reference_hz
+  pub reference_key: u8,
+  pub reference_hz: f32,
+  // The degree each note plays, counting from middle, and repeating
+  // every so many notes; None for notes that don't play. If empty,
+  // every note plays the degree after the one below.
+  pub mapping: Vec<Option<usize>>,
+  // How many degrees the mapping goes up each time it repeats; 0 for
+  // the scale's own repeat
+  pub octave_degree: usize,
+}
+
+impl Default for KeyboardMap {
+  fn default() -> Self {
+    KeyboardMap {
+      first: 0,
+      last: 127,
+      middle: 60,
+      reference_key: 69,
+      reference_hz: 440.0,
+      mapping: vec![],
+      octave_degree: 0,
+    }
+  }
+}
+
+impl KeyboardMap {
+  // Whether a degree or the octave degree is past MAX_DEGREE
+  fn too_far(&self) -> bool {
+    self.octave_degree > MAX_DEGREE || self.mapping.iter().flatten().any(|&d| d > MAX_DEGREE)
+  }
+}
+
+impl Params for Tuning {
+  fn param_mut(&mut self, path: &[Key]) -> Option<&mut f32> {
+    match param::field(path)? {
+      ("keyboard", rest) => match param::field(rest)? {
+        ("reference_hz", []) => Some(&mut self.keyboard.reference_hz),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+}
+
+impl Tuning {
+  // How many degrees the scale has before it repeats
+  fn len(&self) -> i32 {
+    match self.scale.len() {
+      0 => 12,
+      len => len as i32,
+    }
+  }
+
+  // Cents above the first degree, for degrees counted from it
+  fn cents(&self, degree: i32) -> f32 {
+    let len = self.len();
+    let (repeats, step) = (degree.div_euclid(len), degree.rem_euclid(len));
+    let (period, within) = match self.scale.last() {
+      None => (1200.0, 100.0 * step as f32),
+      Some(&period) => (period, self.scale_cents(step as usize)),
+    };
+    repeats as f32 * period + within
+  }
+
+  fn scale_cents(&self, step: usize) -> f32 {
+    match step {
+      0 => 0.0,
+      step => self.scale[step - 1],
+    }
+  }
+
+  // The degree `key` plays, ignoring first and last
+  fn degree(&self, key: u8) -> Option<i32> {
+    let map = &self.keyboard;
+    let offset = key as i32 - map.middle as i32;
+    if map.mapping.is_empty() {
+      return Some(offset);
+    }
+    let size = map.mapping.len() as i32;
+    let octave_degree = match map.octave_degree {
+      0 => self.len(),
+      degree => degree as i32,
+    };
+    let degree = map.mapping[offset.rem_euclid(size) as usize]?;
+    Some(offset.div_euclid(size) * octave_degree + degree as i32)
+  }
+
+  // None for notes that don't play
+  pub fn freq(&self, key: u8) -> Option<f32> {
+    let map = &self.keyboard;
+    if key < map.first || key > map.last {
+      return None;
+    }
+    let cents = self.cents(self.degree(key)?) - self.cents(self.degree(map.reference_key)?);
+    Some(map.reference_hz.max(MIN_REFERENCE_HZ) * (cents / 1200.0).exp2())
+  }
+
+  pub fn check(&self) -> Result<(), String> {
+    if self.scale.iter().any(|cents| !cents.is_finite()) {
+      return Err("has a degree that isn't a number".to_string());
+    }
+    if self.scale.last().is_some_and(|&period| period <= 0.0) {
+      return Err("doesn't go up before it repeats".to_string());
+    }
+    let map = &self.keyboard;
+    if map.first > map.last || map.last > 127 || map.middle > 127 || map.reference_key > 127 {
+      return Err("maps notes midi doesn't have".to_string());
+    }
+    if map.mapping.len() > NUM_KEYS {
+      return Err("has a longer map than midi has notes".to_string());
+    }
+    if map.too_far() {
+      return Err(format!("maps a note past degree {MAX_DEGREE}"));
+    }
+    if !map.reference_hz.is_finite() || map.reference_hz <= 0.0 {
+      return Err("has a reference frequency that isn't a frequency".to_string());
+    }
+    if self.degree(map.reference_key).is_none() {
+      return Err("doesn't map its reference key".to_string());
+    }
+    Ok(())
+  }
+}
+
+// The frequency `key` plays at, in `tuning` if there is one
+pub fn freq(tuning: Option<&Tuning>, key: u8) -> Option<f32> {
+  match tuning {
+    Some(tuning) => tuning.freq(key),
+    None => Some(util::freq_of_pitch(key)),
+  }
+}
+
+// The lines of a Scala file that aren't comments
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+  text.lines().filter(|line| !line.starts_with('!'))
+}
+
+fn number<T: std::str::FromStr>(line: Option<&str>, what: &str) -> anyhow::Result<T> {
+  let line = line.ok_or_else(|| anyhow!("no {what}"))?;
+  let word = line.split_whitespace().next().unwrap_or("");
+  word.parse().map_err(|_| anyhow!("bad {what} {line:?}"))
+}
+
+// A degree is either in cents, with a decimal point, or a ratio, like
+// 3/2 or 2
+fn cents_of_pitch(line: &str) -> anyhow::Result<f32> {
+  let word = line.split_whitespace().next().unwrap_or("");
+  let bad = || anyhow!("bad pitch {line:?}");
+  if word.contains('.') {
+    return word.parse().map_err(|_| bad());
+  }
+  let (num, den) = word.split_once('/').unwrap_or((word, "1"));
+  let num: f64 = num.parse().map_err(|_| bad())?;
+  let den: f64 = den.parse().map_err(|_| bad())?;
+  if num <= 0.0 || den <= 0.0 {
+    return Err(bad());
+  }
+  Ok((1200.0 * (num / den).log2()) as f32)
+}
+
+pub fn parse_scl(text: &str) -> anyhow::Result<Vec<f32>> {
+  let mut lines = lines(text);
+  lines.next().ok_or_else(|| anyhow!("no description"))?;
+  let count: usize = number(lines.next(), "number of notes")?;
+  let scale = lines
+    .filter(|line| !line.trim().is_empty())
+    .take(count)
+    .map(cents_of_pitch)
+    .collect::<anyhow::Result<Vec<f32>>>()?;
+  if scale.len() != count {
+    bail!("{count} notes promised, but {} given", scale.len());
+  }
+  Ok(scale)
+}
+
+pub fn parse_kbm(text: &str) -> anyhow::Result<KeyboardMap> {
+  let mut lines = lines(text);
+  let size: usize = number(lines.next(), "map size")?;
+  // A map repeats every `size` notes, so a longer one than midi has
+  // notes for can't mean anything
+  if size > NUM_KEYS {
+    bail!("map size {size} is more than the {NUM_KEYS} notes midi has");
+  }
+  let mut map = KeyboardMap {
+    first: number(lines.next(), "first note")?,
+    last: number(lines.next(), "last note")?,
+    middle: number(lines.next(), "middle note")?,
+    reference_key: number(lines.next(), "reference note")?,
+    reference_hz: number(lines.next(), "reference frequency")?,
+    octave_degree: number(lines.next(), "octave degree")?,
+    mapping: vec![],
+  };
+  // Notes the file doesn't get to don't play
+  for line in lines.take(size) {
+    map.mapping.push(match line.split_whitespace().next() {
+      Some("x") => None,
+      _ => Some(number(Some(line), "degree")?),
+    });
+  }
+  map.mapping.resize(size, None);
+  if map.too_far() {
+    bail!("degrees past {MAX_DEGREE} aren't supported");
+  }
+  Ok(map)
+}
+
+// A Scala file called `name`, with `extension`, from TUNING_DIR
+pub fn load(name: &str, extension: &str) -> anyhow::Result<String> {
+  let path: PathBuf = crate::patch::path_in(Path::new(TUNING_DIR), name, extension)?;
+  std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_kbm, parse_scl, KeyboardMap, Tuning};
+  use crate::param::{self, Params};
+  use crate::util;
+
+  const JUST: &str = "! just.scl
+!
+5-limit just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2
+";
+
+  #[test]
+  fn parses_scala_files() {
+    let scale = parse_scl(JUST).unwrap();
+    assert_eq!(scale.len(), 7);
+    assert!((scale[3] - 701.955).abs() < 1e-3);
+    assert_eq!(scale[6], 1200.0);
+    assert!(parse_scl("short\n 3\n 100.0\n 2/1\n").is_err());
+
+    let kbm =
+      "! white keys only\n12\n0\n127\n60\n69\n440.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+    let map = parse_kbm(kbm).unwrap();
+    assert_eq!(map.mapping.len(), 12);
+    assert_eq!(map.mapping[1], None);
+    assert_eq!(map.mapping[11], Some(6));
+    assert_eq!(map.octave_degree, 7);
+    assert!(parse_kbm("100000000\n0\n127\n60\n69\n440.0\n0\n").is_err());
+    assert!(parse_kbm("1\n0\n127\n60\n69\n440.0\n100000000\n0\n").is_err());
+    assert!(parse_kbm("1\n0\n127\n60\n69\n440.0\n0\n100000000\n").is_err());
+  }
+
+  #[test]
+  fn tunes_notes() {
+    let equal = Tuning::default();
+    for key in [0, 21, 60, 69, 127] {
+      assert!((equal.freq(key).unwrap() / util::freq_of_pitch(key) - 1.0).abs() < 1e-5);
+    }
+
+    // C major in just intonation on the white keys, with C at 264 Hz
+    let kbm = "12\n0\n127\n60\n60\n264.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+    let just = Tuning {
+      scale: parse_scl(JUST).unwrap(),
+      keyboard: parse_kbm(kbm).unwrap(),
+    };
+    assert_eq!(just.check(), Ok(()));
+    let hz = |key| just.freq(key).unwrap();
+    assert!((hz(60) - 264.0).abs() < 1e-3);
+    assert!((hz(67) - 396.0).abs() < 1e-3);
+    assert!((hz(69) - 440.0).abs() < 1e-3);
+    assert!((hz(72) - 528.0).abs() < 1e-3);
+    assert!((hz(59) - 247.5).abs() < 1e-3);
+    assert_eq!(just.freq(61), None);
+
+    let unmapped = Tuning {
+      keyboard: KeyboardMap {
+        reference_key: 61,
+        ..just.keyboard.clone()
+      },
+      ..just.clone()
+    };
+    assert!(unmapped.check().is_err());
+    let far = Tuning {
+      keyboard: KeyboardMap {
+        octave_degree: usize::MAX,
+        ..just.keyboard.clone()
+      },
+      ..just.clone()
+    };
+    assert!(far.check().is_err());
+
+    // automation can get past check, but not below a frequency
+    let mut moved = equal.clone();
+    for hz in [0.0, -440.0, f32::NAN] {
+      *moved
+        .param_mut(&param::parse_path("keyboard.reference_hz"))
+        .unwrap() = hz;
+      assert!(moved.freq(69).unwrap() > 0.0);
+    }
+  }
+
+  #[test]
+  fn example_files_parse() {
+    let tuning = Tuning {
+      scale: parse_scl(include_str!("../tunings/just.scl")).unwrap(),
+      keyboard: parse_kbm(include_str!("../tunings/white-keys.kbm")).unwrap(),
+    };
+    assert_eq!(tuning.check(), Ok(()));
+    assert!((tuning.freq(60).unwrap() - 264.0).abs() < 1e-3);
+  }
+}
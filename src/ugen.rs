@@ -2,39 +2,144 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::allpass::AllpassState;
+use crate::consts::BUS_DRY;
 use crate::drum::DrumSynthState;
+use crate::fade::Fade;
 use crate::gain::GainState;
+use crate::lfo::LfoState;
 use crate::lowpass::LowpassState;
 use crate::meter::MeterState;
 use crate::midi_manager::MidiManagerState;
 use crate::notegen::NoteMode;
+use crate::pan::PanState;
 use crate::reasonable_synth::ReasonableSynthState;
 use crate::reverb::ReverbState;
-use crate::state::{ControlBlocks, GenState};
+use crate::state::{ControlBlocks, ControlSlots, GenState};
 use crate::ugen_group::UgenGroupState;
 
 #[derive(Debug)]
 pub struct Advice {
   pub note_mode: NoteMode,
+  // From the midi manager playing the note: the pitch bend wheel,
+  // from -1 to 1, and the mod wheel, from 0 to 1
+  pub bend: f32,
+  pub mod_wheel: f32,
 }
 
 pub trait Ugen: std::fmt::Debug + Sync + Send {
   fn run(&mut self, gen: GenState, tick_s: f32, ctl: &ControlBlocks) -> bool;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(tag = "t")]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
 #[ts(export)]
+// Filters take an optional channel count, which defaults to every
+// channel on the bus. With fewer channels than the bus has, the
+// filter hears a downmix of its input, and its output is copied to
+// every channel; e.g. channels: 1 runs a single mono filter.
 pub enum UgenSpec {
-  LowPass { src: usize, dst: usize, ci: usize },
-  AllPass { src: usize, dst: usize, ci: usize },
-  MidiManager { dst: usize, ci: usize },
-  UgenGroup { dst: usize },
-  Meter { src: usize },
-  Gain { src: usize, dst: usize, ci: usize },
-  Reverb { src: usize, dst: usize, ci: usize },
+  LowPass {
+    src: usize,
+    dst: usize,
+    ci: String,
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    channels: Option<usize>,
+  },
+  AllPass {
+    src: usize,
+    dst: usize,
+    ci: String,
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    channels: Option<usize>,
+  },
+  // Plays the notes on midi channels `channels`, from 0 to 15, or on
+  // every channel if that's not given
+  MidiManager {
+    dst: usize,
+    ci: String,
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    channels: Option<Vec<u8>>,
+  },
+  UgenGroup {
+    dst: usize,
+  },
+  Meter {
+    src: usize,
+  },
+  Gain {
+    src: usize,
+    dst: usize,
+    ci: String,
+    #[serde(default)]
+    #[ts(optional = nullable)]
+    channels: Option<usize>,
+  },
+  Reverb {
+    src: usize,
+    dst: usize,
+    ci: String,
+  },
+  Pan {
+    src: usize,
+    dst: usize,
+    ci: String,
+  },
+  // A modulation source, which has no busses; see modulation.rs
+  Lfo {
+    ci: String,
+  },
+}
+
+impl UgenSpec {
+  // Busses this ugen reads
+  pub fn sources(&self) -> Vec<usize> {
+    match *self {
+      UgenSpec::LowPass { src, .. }
+      | UgenSpec::AllPass { src, .. }
+      | UgenSpec::Gain { src, .. }
+      | UgenSpec::Reverb { src, .. }
+      | UgenSpec::Pan { src, .. }
+      | UgenSpec::Meter { src } => vec![src],
+      UgenSpec::MidiManager { .. } | UgenSpec::UgenGroup { .. } | UgenSpec::Lfo { .. } => vec![],
+    }
+  }
+
+  // Busses this ugen writes
+  pub fn destinations(&self) -> Vec<usize> {
+    match *self {
+      UgenSpec::LowPass { dst, .. }
+      | UgenSpec::AllPass { dst, .. }
+      | UgenSpec::Gain { dst, .. }
+      | UgenSpec::Reverb { dst, .. }
+      | UgenSpec::Pan { dst, .. }
+      | UgenSpec::MidiManager { dst, .. } => vec![dst],
+      // XXX drums always play into BUS_DRY, whatever the group's dst
+      UgenSpec::UgenGroup { dst } => vec![dst, BUS_DRY],
+      UgenSpec::Meter { .. } | UgenSpec::Lfo { .. } => vec![],
+    }
+  }
+
+  // The name of the control block this ugen reads, if any, and the
+  // kind of control block it expects to find there. Kinds are named as
+  // in ControlBlock::kind.
+  pub fn control_block(&self) -> Option<(&str, &'static str)> {
+    match self {
+      UgenSpec::LowPass { ci, .. } => Some((ci, "Low")),
+      UgenSpec::AllPass { ci, .. } => Some((ci, "All")),
+      // for the notes it creates
+      UgenSpec::MidiManager { ci, .. } => Some((ci, "Reasonable")),
+      UgenSpec::Gain { ci, .. } => Some((ci, "Gain")),
+      UgenSpec::Reverb { ci, .. } => Some((ci, "Reverb")),
+      UgenSpec::Pan { ci, .. } => Some((ci, "Pan")),
+      UgenSpec::Lfo { ci } => Some((ci, "Lfo")),
+      UgenSpec::UgenGroup { .. } | UgenSpec::Meter { .. } => None,
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -46,8 +151,10 @@ pub enum UgenState {
   UgenGroup(UgenGroupState),
   Meter(MeterState),
   Gain(GainState),
+  Pan(PanState),
   ReasonableSynth(ReasonableSynthState),
   Reverb(ReverbState),
+  Lfo(LfoState),
 }
 
 // some boilerplate to wire things up
@@ -61,24 +168,69 @@ impl Ugen for UgenState {
       UgenState::UgenGroup(s) => s.run(gen, tick_s, ctl),
       UgenState::Meter(s) => s.run(gen, tick_s, ctl),
       UgenState::Gain(s) => s.run(gen, tick_s, ctl),
+      UgenState::Pan(s) => s.run(gen, tick_s, ctl),
       UgenState::ReasonableSynth(s) => s.run(gen, tick_s, ctl),
       UgenState::Reverb(s) => s.run(gen, tick_s, ctl),
+      UgenState::Lfo(s) => s.run(gen, tick_s, ctl),
     }
   }
 }
 
 impl UgenState {
-  pub fn new(spec: UgenSpec) -> Self {
+  // `slots` has to have a slot for every control block `spec` uses
+  pub fn new(spec: UgenSpec, sample_rate_hz: u32, slots: &ControlSlots) -> Self {
     match spec {
-      UgenSpec::LowPass { src, dst, ci } => UgenState::Lowpass(LowpassState::new(src, dst, ci)),
-      UgenSpec::AllPass { src, dst, ci } => UgenState::Allpass(AllpassState::new(src, dst, ci)),
-      UgenSpec::MidiManager { dst, ci } => UgenState::MidiManager(MidiManagerState::new(dst, ci)),
+      UgenSpec::LowPass {
+        src,
+        dst,
+        ci,
+        channels,
+      } => UgenState::Lowpass(LowpassState::new(src, dst, slots[&ci], channels)),
+      UgenSpec::AllPass {
+        src,
+        dst,
+        ci,
+        channels,
+      } => UgenState::Allpass(AllpassState::new(src, dst, slots[&ci], channels)),
+      UgenSpec::MidiManager { dst, ci, channels } => {
+        UgenState::MidiManager(MidiManagerState::new(dst, slots[&ci], channels.as_deref()))
+      },
       UgenSpec::UgenGroup { dst } => UgenState::UgenGroup(UgenGroupState::new(dst)),
-      UgenSpec::Meter { src } => UgenState::Meter(MeterState::new(src)),
-      UgenSpec::Gain { src, dst, ci } => UgenState::Gain(GainState::new(src, dst, ci)),
-      UgenSpec::Reverb { src, dst, ci } => UgenState::Reverb(ReverbState::new(src, dst, ci)),
+      UgenSpec::Meter { src } => UgenState::Meter(MeterState::new(src, sample_rate_hz)),
+      UgenSpec::Gain {
+        src,
+        dst,
+        ci,
+        channels,
+      } => UgenState::Gain(GainState::new(src, dst, slots[&ci], channels)),
+      UgenSpec::Reverb { src, dst, ci } => {
+        UgenState::Reverb(ReverbState::new(src, dst, slots[&ci], sample_rate_hz))
+      },
+      UgenSpec::Pan { src, dst, ci } => UgenState::Pan(PanState::new(src, dst, slots[&ci])),
+      UgenSpec::Lfo { ci } => UgenState::Lfo(LfoState::new(slots[&ci])),
     }
   }
 }
 
-pub type UgensState = Vec<UgenState>;
+pub type NodeId = usize;
+
+// A ugen in the running graph. Its id stays the same for as long as
+// the ugen does, across reconfigurations; see ControlState::reconfigure.
+#[derive(Debug)]
+pub struct Node {
+  pub id: NodeId,
+  // Boxed so that the audio thread can pass Nodes around cheaply
+  pub ugen: Box<UgenState>,
+  // If the ugen is on its way into or out of the graph
+  pub fade: Option<Fade>,
+}
+
+impl Node {
+  // Ugens that are on their way out still make sound, but shouldn't
+  // be given anything new to do.
+  pub fn fading_out(&self) -> bool {
+    self.fade.as_ref().is_some_and(|fade| fade.fading_out())
+  }
+}
+
+pub type UgensState = Vec<Node>;
@@ -0,0 +1,655 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::automation::{Curve, Lane, NamedLanes};
+use crate::consts::AUDIO_BUS_LENGTH;
+use crate::graph;
+use crate::midi::{MOD_WHEEL, NUM_CHANNELS};
+use crate::midi_learn::{MidiBinding, MidiTarget};
+use crate::modulation::{ModDestination, ModRoute, ModSource};
+use crate::param::{self, Params};
+use crate::reasonable_synth::ReasonableControlBlock;
+use crate::sequencer::{SEQ_NUM_INSTRS, SEQ_PATTERN_LEN};
+use crate::state::{ControlBlock, NamedControlBlocks};
+use crate::ugen::UgenSpec;
+
+// Checks on what clients ask of the engine. The audio thread indexes
+// busses directly, so anything that would have it index out of range
+// has to be caught here, before it's sent.
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "t")]
+#[serde(rename_all = "camelCase")]
+#[derive(TS)]
+#[ts(export)]
+pub enum ValidationError {
+  // `ugen` is an index into the list of specs
+  NoSuchBus {
+    ugen: usize,
+    bus: usize,
+  },
+  NoSuchControlBlock {
+    name: String,
+  },
+  NoSuchChannel {
+    ugen: usize,
+    channel: u8,
+  },
+  // Step `pat` of instrument `inst` in the sequencer
+  NoSuchStep {
+    inst: usize,
+    pat: usize,
+  },
+  // Something wants control block `name` to be of kind `expected`,
+  // but it's of kind `found`, or something else wants that.
+  ControlBlockMismatch {
+    name: String,
+    expected: String,
+    found: String,
+  },
+  ControlBlockInUse {
+    name: String,
+    ugen: usize,
+  },
+  // Automation lane `lane` moves one of its numbers
+  ControlBlockAutomated {
+    name: String,
+    lane: String,
+  },
+  // Row `route` of the modulation matrix uses it
+  ControlBlockModulated {
+    name: String,
+    route: usize,
+  },
+  // Midi controller `controller` sets one of its numbers
+  ControlBlockBound {
+    name: String,
+    controller: u8,
+  },
+  // See param.rs for what a path is
+  NoSuchParam {
+    name: String,
+    path: String,
+  },
+  BadParamValue {
+    name: String,
+    path: String,
+    value: f32,
+  },
+  BadLane {
+    name: String,
+    reason: String,
+  },
+  // `route` is an index into the modulation matrix
+  BadRoute {
+    route: usize,
+    reason: String,
+  },
+  BadMidiBinding {
+    reason: String,
+  },
+  BadTuning {
+    name: String,
+    reason: String,
+  },
+  // Saving or loading patch `name` failed
+  BadPatch {
+    name: String,
+    reason: String,
+  },
+  // These ugens are in a cycle, or downstream of one
+  Cycle {
+    ugens: Vec<usize>,
+  },
+}
+
+impl fmt::Display for ValidationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ValidationError::NoSuchBus { ugen, bus } => write!(
+        f,
+        "ugen {} uses bus {}, but there are only {}",
+        ugen, bus, AUDIO_BUS_LENGTH
+      ),
+      ValidationError::NoSuchChannel { ugen, channel } => write!(
+        f,
+        "ugen {} plays midi channel {}, but there are only {}",
+        ugen, channel, NUM_CHANNELS
+      ),
+      ValidationError::NoSuchStep { inst, pat } => write!(
+        f,
+        "no step {} of instrument {}, the sequencer has {} steps of {}",
+        pat, inst, SEQ_PATTERN_LEN, SEQ_NUM_INSTRS
+      ),
+      ValidationError::NoSuchControlBlock { name } => {
+        write!(f, "no control block called {:?}", name)
+      },
+      ValidationError::ControlBlockMismatch {
+        name,
+        expected,
+        found,
+      } => write!(
+        f,
+        "control block {:?} should be {}, but is {}",
+        name, expected, found
+      ),
+      ValidationError::ControlBlockInUse { name, ugen } => {
+        write!(f, "control block {:?} is used by ugen {}", name, ugen)
+      },
+      ValidationError::ControlBlockAutomated { name, lane } => {
+        write!(
+          f,
+          "control block {:?} is automated by lane {:?}",
+          name, lane
+        )
+      },
+      ValidationError::ControlBlockModulated { name, route } => {
+        write!(
+          f,
+          "control block {:?} is used by modulation route {}",
+          name, route
+        )
+      },
+      ValidationError::ControlBlockBound { name, controller } => write!(
+        f,
+        "control block {:?} is set by midi controller {}",
+        name, controller
+      ),
+      ValidationError::NoSuchParam { name, path } => {
+        write!(f, "control block {:?} has no number at {:?}", name, path)
+      },
+      ValidationError::BadParamValue { name, path, value } => write!(
+        f,
+        "{} won't do for {:?} in control block {:?}",
+        value, path, name
+      ),
+      ValidationError::BadLane { name, reason } => {
+        write!(f, "automation lane {:?} {}", name, reason)
+      },
+      ValidationError::BadRoute { route, reason } => {
+        write!(f, "modulation route {} {}", route, reason)
+      },
+      ValidationError::BadTuning { name, reason } => {
+        write!(f, "tuning of control block {:?} {}", name, reason)
+      },
+      ValidationError::BadPatch { name, reason } => write!(f, "patch {:?} {}", name, reason),
+      ValidationError::BadMidiBinding { reason } => write!(f, "midi binding {}", reason),
+      ValidationError::Cycle { ugens } => write!(f, "ugens {:?} form a cycle", ugens),
+    }
+  }
+}
+
+impl Error for ValidationError {}
+
+fn check_kind(name: &str, expected: &str, found: &str) -> Result<(), ValidationError> {
+  if expected != found {
+    return Err(ValidationError::ControlBlockMismatch {
+      name: name.to_string(),
+      expected: expected.to_string(),
+      found: found.to_string(),
+    });
+  }
+  Ok(())
+}
+
+// A graph is ok if every bus it mentions exists, and every ugen
+// sharing a control block agrees with the others, and with what's
+// there already, on what kind of block it is, and it has no cycles.
+// Control blocks that don't exist yet are fine; ugens reading them
+// are silent until they're set.
+pub fn check_specs(
+  specs: &[UgenSpec],
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  let mut wanted: HashMap<&str, &str> = HashMap::new();
+  for (ugen, spec) in specs.iter().enumerate() {
+    let mut busses = spec.sources().into_iter().chain(spec.destinations());
+    if let Some(bus) = busses.find(|&bus| bus >= AUDIO_BUS_LENGTH) {
+      return Err(ValidationError::NoSuchBus { ugen, bus });
+    }
+    if let UgenSpec::MidiManager {
+      channels: Some(channels),
+      ..
+    } = spec
+    {
+      if let Some(&channel) = channels.iter().find(|&&ch| ch >= NUM_CHANNELS) {
+        return Err(ValidationError::NoSuchChannel { ugen, channel });
+      }
+    }
+    if let Some((name, kind)) = spec.control_block() {
+      let other = *wanted.entry(name).or_insert(kind);
+      check_kind(name, other, kind)?;
+      if let Some(ctl) = control_blocks.get(name) {
+        check_kind(name, kind, ctl.kind())?;
+      }
+    }
+  }
+  graph::execution_order(specs)?;
+  Ok(())
+}
+
+// A control block can be written as long as no ugen in the graph is
+// expecting some other kind there.
+pub fn check_control_block(
+  name: &str,
+  ctl: &ControlBlock,
+  specs: &[UgenSpec],
+) -> Result<(), ValidationError> {
+  for spec in specs {
+    if let Some((ci, kind)) = spec.control_block() {
+      if ci == name {
+        check_kind(name, kind, ctl.kind())?;
+      }
+    }
+  }
+  if let ControlBlock::Reasonable(ReasonableControlBlock {
+    tuning: Some(tuning),
+    ..
+  }) = ctl
+  {
+    tuning
+      .check()
+      .map_err(|reason| ValidationError::BadTuning {
+        name: name.to_string(),
+        reason,
+      })?;
+  }
+  Ok(())
+}
+
+// A control block can be deleted if it exists and nothing uses it.
+pub fn check_delete(
+  name: &str,
+  specs: &[UgenSpec],
+  control_blocks: &NamedControlBlocks,
+  lanes: &NamedLanes,
+  routes: &[ModRoute],
+  bindings: &[MidiBinding],
+) -> Result<(), ValidationError> {
+  if !control_blocks.contains_key(name) {
+    return Err(ValidationError::NoSuchControlBlock {
+      name: name.to_string(),
+    });
+  }
+  let user = specs
+    .iter()
+    .position(|spec| matches!(spec.control_block(), Some((ci, _)) if ci == name));
+  if let Some(ugen) = user {
+    return Err(ValidationError::ControlBlockInUse {
+      name: name.to_string(),
+      ugen,
+    });
+  }
+  if let Some((lane, _)) = lanes.iter().find(|(_, lane)| lane.block == name) {
+    return Err(ValidationError::ControlBlockAutomated {
+      name: name.to_string(),
+      lane: lane.clone(),
+    });
+  }
+  if let Some(route) = routes
+    .iter()
+    .position(|route| route_blocks(route).contains(&name))
+  {
+    return Err(ValidationError::ControlBlockModulated {
+      name: name.to_string(),
+      route,
+    });
+  }
+  if let Some(binding) = bindings.iter().find(|b| b.target.block == name) {
+    return Err(ValidationError::ControlBlockBound {
+      name: name.to_string(),
+      controller: binding.controller,
+    });
+  }
+  Ok(())
+}
+
+// The control blocks a route refers to
+fn route_blocks(route: &ModRoute) -> Vec<&str> {
+  let mut blocks = vec![];
+  if let ModSource::Lfo { name } = &route.source {
+    blocks.push(name.as_str());
+  }
+  match &route.destination {
+    ModDestination::Param { block, .. } | ModDestination::Voice { block, .. } => {
+      blocks.push(block.as_str())
+    },
+  }
+  blocks
+}
+
+// Automation, modulation and midi controllers can only move f32s, in
+// control blocks that exist
+fn check_param(
+  name: &str,
+  path: &str,
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  let Some(ctl) = control_blocks.get(name) else {
+    return Err(ValidationError::NoSuchControlBlock {
+      name: name.to_string(),
+    });
+  };
+  if ctl.clone().param_mut(&param::parse_path(path)).is_none() {
+    return Err(ValidationError::NoSuchParam {
+      name: name.to_string(),
+      path: path.to_string(),
+    });
+  }
+  Ok(())
+}
+
+// A lane has to move an f32 in a control block that exists, through
+// breakpoints in time order, with exponential segments that never
+// reach or cross zero.
+pub fn check_lane(
+  name: &str,
+  lane: &Lane,
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  let bad = |reason: &str| {
+    Err(ValidationError::BadLane {
+      name: name.to_string(),
+      reason: reason.to_string(),
+    })
+  };
+  check_param(&lane.block, &lane.path, control_blocks)?;
+  if lane.points.is_empty() {
+    return bad("has no breakpoints");
+  }
+  let mut prev: Option<(f32, f32)> = None;
+  for point in lane.points.iter() {
+    if !point.time_s.is_finite() || point.time_s < 0.0 || !point.value.is_finite() {
+      return bad("has a breakpoint that isn't a number");
+    }
+    if let Some((time_s, value)) = prev {
+      if point.time_s < time_s {
+        return bad("has breakpoints out of order");
+      }
+      if point.curve == Curve::Exp && (value * point.value <= 0.0) {
+        return bad("has an exponential segment through zero");
+      }
+    }
+    prev = Some((point.time_s, point.value));
+  }
+  Ok(())
+}
+
+// A midi controller can set an f32 in a control block that exists,
+// over a range that an exponential curve can cover if it has one.
+pub fn check_midi_target(
+  target: &MidiTarget,
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  let bad = |reason: &str| {
+    Err(ValidationError::BadMidiBinding {
+      reason: reason.to_string(),
+    })
+  };
+  check_param(&target.block, &target.path, control_blocks)?;
+  if !target.min.is_finite() || !target.max.is_finite() {
+    return bad("has a range that isn't a number");
+  }
+  if target.curve == Curve::Exp && target.min * target.max <= 0.0 {
+    return bad("has an exponential curve through zero");
+  }
+  Ok(())
+}
+
+// The sustain pedal and the mod wheel already do something
+pub fn check_midi_bindings(
+  bindings: &[MidiBinding],
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  for binding in bindings {
+    if binding.controller >= 128 || binding.controller == 0x40 || binding.controller == MOD_WHEEL {
+      return Err(ValidationError::BadMidiBinding {
+        reason: format!("can't use controller {}", binding.controller),
+      });
+    }
+    check_midi_target(&binding.target, control_blocks)?;
+  }
+  Ok(())
+}
+
+pub fn check_sequencer_step(inst: usize, pat: usize) -> Result<(), ValidationError> {
+  if inst >= SEQ_NUM_INSTRS || pat >= SEQ_PATTERN_LEN {
+    return Err(ValidationError::NoSuchStep { inst, pat });
+  }
+  Ok(())
+}
+
+// Every row of a modulation matrix has to read from an LFO's control
+// block or a midi controller, or, if it modulates voices, from the
+// voice, and write to an f32 in a control block that exists, or to the
+// voices of a Reasonable one.
+pub fn check_routes(
+  routes: &[ModRoute],
+  control_blocks: &NamedControlBlocks,
+) -> Result<(), ValidationError> {
+  let block = |name: &str, kind: &str| match control_blocks.get(name) {
+    Some(ctl) => check_kind(name, kind, ctl.kind()).map(|_| ctl),
+    None => Err(ValidationError::NoSuchControlBlock {
+      name: name.to_string(),
+    }),
+  };
+  for (ix, route) in routes.iter().enumerate() {
+    let bad = |reason: &str| {
+      Err(ValidationError::BadRoute {
+        route: ix,
+        reason: reason.to_string(),
+      })
+    };
+    if !route.depth.is_finite() {
+      return bad("has a depth that isn't a number");
+    }
+    let per_voice = matches!(route.source, ModSource::Velocity | ModSource::Key);
+    match &route.source {
+      ModSource::Lfo { name } => {
+        block(name, "Lfo")?;
+      },
+      ModSource::Cc { controller } if *controller >= 128 => {
+        return bad("reads a midi controller that doesn't exist");
+      },
+      ModSource::Cc { .. } | ModSource::Velocity | ModSource::Key => (),
+    }
+    match &route.destination {
+      ModDestination::Param { .. } if per_voice => {
+        return bad("reads velocity or key, which only voices have");
+      },
+      ModDestination::Param { block: name, path } => {
+        check_param(name, path, control_blocks)?;
+      },
+      ModDestination::Voice { block: name, .. } => {
+        block(name, "Reasonable")?;
+      },
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    check_control_block, check_delete, check_lane, check_sequencer_step, check_specs,
+    ValidationError,
+  };
+  use crate::automation::{Breakpoint, Curve, Lane, NamedLanes};
+  use crate::consts::{AUDIO_BUS_LENGTH, BUS_OUT};
+  use crate::envelope::Adsr;
+  use crate::gain::GainControlBlock;
+  use crate::midi_manager::Polyphony;
+  use crate::param::set_param;
+  use crate::reasonable_synth::{ReasonableControlBlock, Wheels};
+  use crate::sequencer::{SEQ_NUM_INSTRS, SEQ_PATTERN_LEN};
+  use crate::state::{ControlBlock, NamedControlBlocks};
+  use crate::tuning::Tuning;
+  use crate::ugen::UgenSpec;
+
+  fn gain(ci: &str) -> UgenSpec {
+    UgenSpec::Gain {
+      src: BUS_OUT,
+      dst: BUS_OUT,
+      ci: ci.to_string(),
+      channels: None,
+    }
+  }
+
+  fn reverb(ci: &str) -> UgenSpec {
+    UgenSpec::Reverb {
+      src: BUS_OUT,
+      dst: BUS_OUT,
+      ci: ci.to_string(),
+    }
+  }
+
+  #[test]
+  fn rejects_missing_busses() {
+    let ctls = NamedControlBlocks::new();
+    assert_eq!(check_specs(&[gain("gain")], &ctls), Ok(()));
+    let bad_bus = UgenSpec::Meter {
+      src: AUDIO_BUS_LENGTH,
+    };
+    assert_eq!(
+      check_specs(&[gain("gain"), bad_bus], &ctls),
+      Err(ValidationError::NoSuchBus {
+        ugen: 1,
+        bus: AUDIO_BUS_LENGTH
+      })
+    );
+    let bad_channel = UgenSpec::MidiManager {
+      dst: BUS_OUT,
+      ci: "synth".to_string(),
+      channels: Some(vec![0, 16]),
+    };
+    assert_eq!(
+      check_specs(&[bad_channel], &ctls),
+      Err(ValidationError::NoSuchChannel {
+        ugen: 0,
+        channel: 16
+      })
+    );
+  }
+
+  #[test]
+  fn rejects_mismatched_blocks() {
+    let mut ctls = NamedControlBlocks::new();
+    assert!(check_specs(&[gain("fx"), reverb("fx")], &ctls).is_err());
+
+    let ctl = ControlBlock::Gain(GainControlBlock { scale: 1.0 });
+    assert_eq!(check_control_block("fx", &ctl, &[gain("fx")]), Ok(()));
+    assert!(check_control_block("fx", &ctl, &[reverb("fx")]).is_err());
+
+    ctls.insert("fx".to_string(), ctl);
+    assert_eq!(check_specs(&[gain("fx")], &ctls), Ok(()));
+    assert!(check_specs(&[reverb("fx")], &ctls).is_err());
+  }
+
+  #[test]
+  fn only_deletes_unused_blocks() {
+    let mut ctls = NamedControlBlocks::new();
+    let ctl = ControlBlock::Gain(GainControlBlock { scale: 1.0 });
+    ctls.insert("fx".to_string(), ctl);
+    assert_eq!(
+      check_delete("fx", &[], &ctls, &NamedLanes::new(), &[], &[]),
+      Ok(())
+    );
+    assert!(check_delete("other", &[], &ctls, &NamedLanes::new(), &[], &[]).is_err());
+    assert_eq!(
+      check_delete(
+        "fx",
+        &[reverb("verb"), gain("fx")],
+        &ctls,
+        &NamedLanes::new(),
+        &[],
+        &[]
+      ),
+      Err(ValidationError::ControlBlockInUse {
+        name: "fx".to_string(),
+        ugen: 1
+      })
+    );
+  }
+
+  #[test]
+  fn checks_lanes() {
+    let mut ctls = NamedControlBlocks::new();
+    let ctl = ControlBlock::Gain(GainControlBlock { scale: 1.0 });
+    ctls.insert("fx".to_string(), ctl);
+    let point = |time_s, value, curve| Breakpoint {
+      time_s,
+      value,
+      curve,
+    };
+    let mut lane = Lane {
+      block: "fx".to_string(),
+      path: "scale".to_string(),
+      points: vec![point(0.0, 1.0, Curve::Linear), point(1.0, 0.1, Curve::Exp)],
+      looped: false,
+    };
+    assert_eq!(check_lane("fade", &lane, &ctls), Ok(()));
+
+    let mut lanes = NamedLanes::new();
+    lanes.insert("fade".to_string(), lane.clone());
+    assert!(check_delete("fx", &[], &ctls, &lanes, &[], &[]).is_err());
+
+    lane.points.push(point(2.0, 0.0, Curve::Exp));
+    assert!(check_lane("fade", &lane, &ctls).is_err());
+    lane.points[2] = point(0.5, 1.0, Curve::Linear);
+    assert!(check_lane("fade", &lane, &ctls).is_err());
+    lane.points.clear();
+    assert!(check_lane("fade", &lane, &ctls).is_err());
+    lane.path = "volume".to_string();
+    assert_eq!(
+      check_lane("fade", &lane, &ctls),
+      Err(ValidationError::NoSuchParam {
+        name: "fx".to_string(),
+        path: "volume".to_string()
+      })
+    );
+  }
+
+  #[test]
+  fn checks_sequencer_steps() {
+    assert_eq!(
+      check_sequencer_step(SEQ_NUM_INSTRS - 1, SEQ_PATTERN_LEN - 1),
+      Ok(())
+    );
+    assert_eq!(
+      check_sequencer_step(SEQ_NUM_INSTRS, 0),
+      Err(ValidationError::NoSuchStep {
+        inst: SEQ_NUM_INSTRS,
+        pat: 0
+      })
+    );
+    assert!(check_sequencer_step(0, SEQ_PATTERN_LEN).is_err());
+  }
+
+  #[test]
+  fn checks_blocks_after_their_params_are_set() {
+    let ctl = ControlBlock::Reasonable(ReasonableControlBlock {
+      adsr: Adsr {
+        attack_s: 0.001,
+        decay_s: 0.1,
+        sustain: 0.5,
+        release_s: 0.1,
+      },
+      wheels: Wheels::default(),
+      polyphony: Polyphony::default(),
+      mono: None,
+      tuning: Some(Tuning::default()),
+    });
+    let path = "tuning.keyboard.reference_hz";
+    let tuned = set_param("synth", &ctl, path, 432.0).unwrap();
+    assert_eq!(check_control_block("synth", &tuned, &[]), Ok(()));
+    let silent = set_param("synth", &ctl, path, 0.0).unwrap();
+    assert!(matches!(
+      check_control_block("synth", &silent, &[]),
+      Err(ValidationError::BadTuning { .. })
+    ));
+  }
+}
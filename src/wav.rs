@@ -0,0 +1,151 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use clap::ValueEnum;
+
+// Sample formats we know how to write into a RIFF/WAV file.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum WavFormat {
+  S16,
+  F32,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+// Size of everything before the sample data, i.e. the RIFF header, a
+// 16-byte "fmt " chunk and the "data" chunk header.
+const HEADER_LEN: u32 = 44;
+
+impl WavFormat {
+  fn bytes_per_sample(&self) -> u16 {
+    match self {
+      WavFormat::S16 => 2,
+      WavFormat::F32 => 4,
+    }
+  }
+
+  fn format_tag(&self) -> u16 {
+    match self {
+      WavFormat::S16 => WAVE_FORMAT_PCM,
+      WavFormat::F32 => WAVE_FORMAT_IEEE_FLOAT,
+    }
+  }
+}
+
+pub fn convert_sample(samp_f32: f32) -> i16 {
+  (samp_f32.clamp(-1.0, 1.0) * 32767.0) as i16
+}
+
+pub struct WavWriter<W: Write + Seek> {
+  out: W,
+  format: WavFormat,
+  data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  pub fn new(
+    mut out: W,
+    channels: u16,
+    sample_rate: u32,
+    format: WavFormat,
+  ) -> anyhow::Result<WavWriter<W>> {
+    let block_align = channels * format.bytes_per_sample();
+    let byte_rate = sample_rate * (block_align as u32);
+
+    // The two length fields get patched up in finish(), once we know
+    // how much data there is.
+    out.write_all(b"RIFF")?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&format.format_tag().to_le_bytes())?;
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&byte_rate.to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&(format.bytes_per_sample() * 8).to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    Ok(WavWriter {
+      out,
+      format,
+      data_len: 0,
+    })
+  }
+
+  // Samples are interleaved, and nominally in [-1, 1].
+  pub fn write_samples(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+    for samp in samples.iter() {
+      match self.format {
+        WavFormat::S16 => self.out.write_all(&convert_sample(*samp).to_le_bytes())?,
+        WavFormat::F32 => self.out.write_all(&samp.to_le_bytes())?,
+      }
+    }
+    self.data_len += (samples.len() as u32) * (self.format.bytes_per_sample() as u32);
+    Ok(())
+  }
+
+  pub fn finish(mut self) -> anyhow::Result<W> {
+    self.out.seek(SeekFrom::Start(4))?;
+    self
+      .out
+      .write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+    self.out.seek(SeekFrom::Start((HEADER_LEN - 4) as u64))?;
+    self.out.write_all(&self.data_len.to_le_bytes())?;
+    self.out.seek(SeekFrom::End(0))?;
+    self.out.flush()?;
+    Ok(self.out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{WavFormat, WavWriter};
+  use std::io::Cursor;
+
+  fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+  }
+
+  fn u16_at(bytes: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(bytes[pos..pos + 2].try_into().unwrap())
+  }
+
+  #[test]
+  fn s16_header_and_data() {
+    let mut wav = WavWriter::new(Cursor::new(vec![]), 2, 44100, WavFormat::S16).unwrap();
+    wav.write_samples(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+    let bytes = wav.finish().unwrap().into_inner();
+
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32_at(&bytes, 4), 36 + 8);
+    assert_eq!(&bytes[8..16], b"WAVEfmt ");
+    assert_eq!(u16_at(&bytes, 20), 1);
+    assert_eq!(u16_at(&bytes, 22), 2);
+    assert_eq!(u32_at(&bytes, 24), 44100);
+    assert_eq!(u32_at(&bytes, 28), 44100 * 4);
+    assert_eq!(u16_at(&bytes, 32), 4);
+    assert_eq!(u16_at(&bytes, 34), 16);
+    assert_eq!(&bytes[36..40], b"data");
+    assert_eq!(u32_at(&bytes, 40), 8);
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(u16_at(&bytes, 46) as i16, 32767);
+    assert_eq!(u16_at(&bytes, 48) as i16, -32767);
+  }
+
+  #[test]
+  fn f32_header_and_data() {
+    let mut wav = WavWriter::new(Cursor::new(vec![]), 1, 48000, WavFormat::F32).unwrap();
+    wav.write_samples(&[0.25, -0.75]).unwrap();
+    let bytes = wav.finish().unwrap().into_inner();
+
+    assert_eq!(u16_at(&bytes, 20), 3);
+    assert_eq!(u16_at(&bytes, 34), 32);
+    assert_eq!(u32_at(&bytes, 40), 8);
+    assert_eq!(f32::from_le_bytes(bytes[48..52].try_into().unwrap()), -0.75);
+  }
+}
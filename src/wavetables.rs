@@ -4,7 +4,7 @@ use rand::Rng;
 
 use crate::synth::TABLE_SIZE;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Wavetables {
   pub saw_wavetable: Arc<Vec<f32>>,
   pub sin_wavetable: Arc<Vec<f32>>,
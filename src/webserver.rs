@@ -1,16 +1,26 @@
+use crate::automation::{Lane, NamedLanes};
 use crate::midi;
-use crate::state::ControlBlock;
+use crate::midi_learn::{MidiBinding, MidiTarget};
+use crate::modulation::ModRoute;
+use crate::state::{ControlBlock, NamedControlBlocks};
 use crate::ugen::UgenSpec;
 use crate::util::UnitHandle;
+use crate::validate::ValidationError;
 use rocket::futures::{SinkExt, StreamExt};
 use rocket::{get, routes};
 use rocket_ws::{stream::DuplexStream, Message as RocketWsMessage, WebSocket};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Sender};
 use ts_rs::TS;
 
 const CHANNEL_CAPACITY: usize = 100;
 
+pub type ClientId = usize;
+
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "t")]
 #[serde(rename_all = "camelCase")]
@@ -19,24 +29,94 @@ const CHANNEL_CAPACITY: usize = 100;
 pub enum WebMessage {
   Quit,
   Drum,
-  SetControlBlock { index: usize, ctl: ControlBlock },
-  SetSequencer { inst: usize, pat: usize, on: bool },
-  Reconfigure { specs: Vec<UgenSpec> },
+  // Control blocks are created by setting them
+  SetControlBlock {
+    name: String,
+    ctl: ControlBlock,
+  },
+  DeleteControlBlock {
+    name: String,
+  },
+  // Changes one number in a control block; see param.rs
+  SetParam {
+    block: String,
+    path: String,
+    value: f32,
+  },
+  // How long parameters take to glide to new values, in seconds
+  SetParamRamp {
+    ramp_s: f32,
+  },
+  ListControlBlocks,
+  // Automation lanes are created by setting them, and start right away
+  SetLane {
+    name: String,
+    lane: Lane,
+  },
+  DeleteLane {
+    name: String,
+  },
+  // Replaces the whole modulation matrix
+  SetModRoutes {
+    routes: Vec<ModRoute>,
+  },
+  // Tunes the notes a Reasonable control block plays, with Scala files
+  // from the tunings directory: `scale` is a .scl file and `keyboard`
+  // a .kbm file, named without their extensions. Without a scale,
+  // it's 12-TET, and without a keyboard mapping, the scale starts at
+  // middle C and A is at reference_hz, or 440 Hz.
+  LoadTuning {
+    block: String,
+    #[ts(optional = nullable)]
+    scale: Option<String>,
+    #[ts(optional = nullable)]
+    keyboard: Option<String>,
+    #[ts(optional = nullable)]
+    reference_hz: Option<f32>,
+  },
+  // Binds the next midi controller to move to `target`, in place of
+  // whatever was bound to it before
+  MidiLearn {
+    target: MidiTarget,
+  },
+  CancelMidiLearn,
+  // Replaces every midi binding
+  SetMidiBindings {
+    bindings: Vec<MidiBinding>,
+  },
+  SetSequencer {
+    inst: usize,
+    pat: usize,
+    on: bool,
+  },
+  Reconfigure {
+    specs: Vec<UgenSpec>,
+  },
+  GetState,
+  // Patches are named files in the patches directory
+  SavePatch {
+    name: String,
+  },
+  LoadPatch {
+    name: String,
+  },
 }
 
 // Messages to the synth, either
-// - from the web client, or
+// - from a web client,
 // - a converse-direction message subscription request, sent once when
-//   we're setting up the websocket connection
+//   we're setting up a websocket connection, or
+// - a notice that a websocket connection has gone away
 
 pub enum WebOrSubMessage {
-  WebMessage(WebMessage),
-  SubMessage(Sender<SynthMessage>),
+  WebMessage(ClientId, WebMessage),
+  SubMessage(ClientId, Sender<SynthMessage>),
+  Unsubscribe(ClientId),
 }
 
 // Messages sent from the synthe to the web client
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(tag = "t")]
 #[serde(rename_all = "camelCase")]
 #[derive(TS)]
@@ -49,6 +129,72 @@ pub enum SynthMessage {
     level: f32, // rms
     peak: f32,
   },
+  EngineStats {
+    xruns: usize,   // since startup
+    load: f32,      // mean time spent rendering a period, as a fraction of the period
+    peak_load: f32, // worst single period since the last report
+  },
+  // Reply to GetState
+  State {
+    specs: Vec<UgenSpec>,
+    control_blocks: NamedControlBlocks,
+    lanes: NamedLanes,
+    mod_routes: Vec<ModRoute>,
+    midi_bindings: Vec<MidiBinding>,
+    sequencer: Vec<Vec<bool>>, // indexed by [pat][inst]
+  },
+  // A MidiLearn has found its controller
+  MidiLearned {
+    binding: MidiBinding,
+  },
+  // Reply to ListControlBlocks
+  ControlBlockNames {
+    names: Vec<String>,
+  },
+  // Reply to a WebMessage that we refused
+  Error {
+    error: ValidationError,
+    description: String,
+  },
+}
+
+// Everyone currently connected over a websocket
+#[derive(Debug, Default)]
+pub struct Clients {
+  senders: Vec<(ClientId, Sender<SynthMessage>)>,
+}
+
+impl Clients {
+  pub fn add(&mut self, id: ClientId, tx: Sender<SynthMessage>) {
+    self.senders.push((id, tx));
+  }
+
+  // Like broadcast, but to just one client
+  pub fn send(&mut self, id: ClientId, msg: SynthMessage) {
+    let closed = match self.senders.iter().find(|(other, _)| *other == id) {
+      Some((_, tx)) => matches!(tx.try_send(msg), Err(TrySendError::Closed(_))),
+      None => false,
+    };
+    if closed {
+      self.remove(id);
+    }
+  }
+
+  pub fn remove(&mut self, id: ClientId) {
+    self.senders.retain(|(other, _)| *other != id);
+  }
+
+  // Sends msg to every client. A client that isn't keeping up just
+  // misses the message, rather than holding up everyone else, and a
+  // client whose connection has closed is forgotten.
+  pub fn broadcast(&mut self, msg: SynthMessage) {
+    self
+      .senders
+      .retain(|(id, tx)| match tx.try_send(msg.clone()) {
+        Ok(()) | Err(TrySendError::Full(_)) => true,
+        Err(TrySendError::Closed(_)) => false,
+      });
+  }
 }
 
 #[get("/ws")]
@@ -61,11 +207,12 @@ async fn ws_serve(
 ) -> rocket_ws::Channel<'static> {
   let web_tx = state.inner().clone();
   let (synth_tx, mut synth_rx) = channel::<SynthMessage>(CHANNEL_CAPACITY);
+  let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
 
-  // Send a "subscription request", i.e. ask the synth to send us messages
-  // instead of any clients that might have come before us.
+  // Send a "subscription request", i.e. ask the synth to send us
+  // messages as well as any other clients that are connected.
   web_tx
-    .send(WebOrSubMessage::SubMessage(synth_tx))
+    .send(WebOrSubMessage::SubMessage(id, synth_tx))
     .await
     .unwrap();
 
@@ -76,7 +223,9 @@ async fn ws_serve(
         // handle messages from synth to client
         while let Some(message) = synth_rx.recv().await {
           let json_str = serde_json::to_string(&message).unwrap();
-          sink.send(RocketWsMessage::Text(json_str)).await.unwrap();
+          if sink.send(RocketWsMessage::Text(json_str)).await.is_err() {
+            break;
+          }
         }
       });
 
@@ -85,6 +234,7 @@ async fn ws_serve(
         match message {
           Err(e) => {
             println!("Getting next websocket message, got error {:?}", e);
+            break;
           },
           Ok(m) => {
             if let RocketWsMessage::Text(t) = &m {
@@ -93,7 +243,10 @@ async fn ws_serve(
                   println!("Parsing msg {}, got JSON parse error {:?}", t, e);
                 },
                 Ok(m) => {
-                  web_tx.send(WebOrSubMessage::WebMessage(m)).await.unwrap();
+                  web_tx
+                    .send(WebOrSubMessage::WebMessage(id, m))
+                    .await
+                    .unwrap();
                 },
               }
             }
@@ -101,6 +254,8 @@ async fn ws_serve(
         }
       }
 
+      // Dropping our sender closes synth_rx, which ends the task above.
+      web_tx.send(WebOrSubMessage::Unsubscribe(id)).await.unwrap();
       Ok(())
     })
   })
@@ -155,6 +310,13 @@ mod tests {
     assert_eq!(json_str, r###"{"t":"quit"}"###);
   }
 
+  #[test]
+  fn get_state_message_serialization() {
+    let message = WebMessage::GetState;
+    let json_str = serde_json::to_string(&message).unwrap();
+    assert_eq!(json_str, r###"{"t":"getState"}"###);
+  }
+
   #[test]
   fn set_volume_message_serialization() {
     let message = WebMessage::SetSequencer {